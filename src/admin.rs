@@ -0,0 +1,239 @@
+//! Administrative endpoints.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use azure_core::auth::TokenCredential;
+use futures::StreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    cache::{self, FsIndex},
+    credential::ReloadableCredential,
+    AppConfig, ConfigCache, Error,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct VerifyParams {
+    /// Remove corrupt entries from the cache
+    #[serde(default)]
+    remove: bool,
+}
+
+/// Endpoint used by operators to view the effective configuration, with secrets redacted.
+pub async fn config(State(config): State<AppConfig>) -> String {
+    format!("{config:#?}")
+}
+
+/// Endpoint used by operators to scan a filesystem cache for corrupt entries.
+pub async fn verify_cache(
+    State(config): State<AppConfig>,
+    State(indexes): State<Arc<HashMap<PathBuf, Arc<FsIndex>>>>,
+    Query(params): Query<VerifyParams>,
+) -> Result<Response, Error> {
+    let Some(ConfigCache::Fs(cache)) = &config.cache else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "cache verification is only supported for filesystem caches",
+        )
+            .into_response());
+    };
+
+    let empty = HashMap::new();
+    let signatures = config
+        .verify_signatures
+        .as_ref()
+        .map_or(&empty, |v| &v.extensions);
+
+    let index = indexes.get(&cache.path).map(Arc::as_ref);
+    let report = cache::verify(cache, index, signatures, params.remove).await?;
+    Ok(Json(report).into_response())
+}
+
+/// The number of entries listed per page by default.
+const DEFAULT_LIST_LIMIT: usize = 1000;
+
+/// The maximum number of entries that may be listed per page.
+const MAX_LIST_LIMIT: usize = 10_000;
+
+#[derive(Deserialize, Debug)]
+pub struct ListParams {
+    /// List the entries after this path (the `next` value of the previous page)
+    after: Option<String>,
+    /// The maximum number of entries to list
+    limit: Option<usize>,
+}
+
+/// Endpoint used by operators to page through the entries of a filesystem cache.
+///
+/// The page is streamed as it is listed, as `{"entries": [...], "next": ...}`, where `next` is
+/// the `after` parameter for the following page (or `null` on the last page).
+pub async fn list_cache(
+    State(config): State<AppConfig>,
+    Query(params): Query<ListParams>,
+) -> Response {
+    let Some(ConfigCache::Fs(cache)) = &config.cache else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "cache listing is only supported for filesystem caches",
+        )
+            .into_response();
+    };
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let entries = Box::pin(cache::walk(cache, params.after));
+
+    let state = (entries, 0usize, None::<String>, false);
+    let body =
+        futures::stream::unfold(state, move |(mut entries, listed, last, done)| async move {
+            if done {
+                return None;
+            }
+
+            let entry = if listed < limit {
+                entries.next().await
+            } else {
+                // Only point at another page if there is anything left on it.
+                let more = entries.next().await.is_some();
+                let next = serde_json::to_string(&last.filter(|_| more)).unwrap();
+
+                let chunk = Bytes::from(format!("],\"next\":{next}}}"));
+                return Some((Ok(chunk), (entries, listed, None, true)));
+            };
+
+            match entry {
+                Some(Ok(entry)) => {
+                    let mut chunk = if listed == 0 {
+                        b"{\"entries\":[".to_vec()
+                    } else {
+                        b",".to_vec()
+                    };
+                    chunk.extend(serde_json::to_vec(&entry).unwrap());
+
+                    Some((
+                        Ok(Bytes::from(chunk)),
+                        (entries, listed + 1, Some(entry.path), false),
+                    ))
+                }
+                Some(Err(e)) => {
+                    let e = e.context("failed to list cache");
+                    error!("{:?}", e);
+
+                    Some((Err(e), (entries, listed, last, true)))
+                }
+                None => {
+                    let prefix = if listed == 0 { "{\"entries\":[" } else { "" };
+
+                    let chunk = Bytes::from(format!("{prefix}],\"next\":null}}"));
+                    Some((Ok(chunk), (entries, listed, None, true)))
+                }
+            }
+        });
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheWrites {
+    /// Whether symbols are written to the cache
+    cache_writes: bool,
+}
+
+/// Endpoint used by operators to resume writing symbols to the cache.
+pub async fn enable_cache_writes(State(writes): State<Arc<AtomicBool>>) -> Json<CacheWrites> {
+    writes.store(true, Ordering::Relaxed);
+    info!("cache writes enabled");
+
+    Json(CacheWrites { cache_writes: true })
+}
+
+/// Endpoint used by operators to stop writing symbols to the cache, while still serving hits.
+pub async fn disable_cache_writes(State(writes): State<Arc<AtomicBool>>) -> Json<CacheWrites> {
+    writes.store(false, Ordering::Relaxed);
+    warn!("cache writes disabled");
+
+    Json(CacheWrites {
+        cache_writes: false,
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReloadReport {
+    success: bool,
+    /// The reason the credentials could not be reloaded, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Endpoint used by operators to rebuild the upstream credentials, e.g. after rotating a secret.
+///
+/// The new credentials are only swapped in once they have acquired a token for every
+/// authenticated server; otherwise the existing credentials are left in place.
+pub async fn reload_credentials(
+    State(config): State<AppConfig>,
+    State(credentials): State<Arc<ReloadableCredential>>,
+) -> Response {
+    let result = async {
+        let token = crate::create_credential(&config)?;
+
+        for server in config.all_servers() {
+            if let Some(auth) = &server.auth {
+                token
+                    .get_token(&[&auth.scope])
+                    .await
+                    .with_context(|| format!("failed to get token for {}", server.url))?;
+            }
+        }
+
+        anyhow::Ok(token)
+    }
+    .await;
+
+    match result {
+        Ok(token) => {
+            credentials.replace(token);
+            info!("reloaded credentials");
+
+            Json(ReloadReport {
+                success: true,
+                error: None,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            let e = e.context("failed to reload credentials");
+            error!("{:?}", e);
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ReloadReport {
+                    success: false,
+                    error: Some(format!("{:#}", e)),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,1435 @@
+//! Symbol cache backends.
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use axum::body::{Body, Bytes};
+use azure_core::{auth::TokenCredential, prelude::Metadata};
+use azure_storage::{CloudLocation, StorageCredentials};
+use azure_storage_blobs::{
+    blob::{BlobBlockType, BlockList},
+    prelude::{BlobClient, ClientBuilder},
+};
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+use url::Url;
+use uuid::Uuid;
+
+#[cfg(feature = "memory-cache")]
+use crate::ConfigMemoryCache;
+use crate::{credential::ScopedCredential, ConfigAzureCache, ConfigCache, ConfigFsCache};
+
+/// The number of attempts made to commit a symbol to the cache before giving up.
+const COMMIT_ATTEMPTS: u32 = 3;
+
+/// The default size of the blocks uploaded to an Azure cache.
+const DEFAULT_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The default size of the write buffer used for filesystem cache entries.
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The extension appended to zstd-compressed entries in a filesystem cache.
+const COMPRESSED_EXTENSION: &str = "zst";
+
+/// The extension of cache files that are still being written, which are renamed into place once
+/// complete so that a truncated file is never served.
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Determine whether `path` is a cache entry, rather than metadata or an incomplete write.
+fn is_entry(path: &Path) -> bool {
+    !path
+        .extension()
+        .is_some_and(|e| e == "meta" || e == PARTIAL_EXTENSION)
+}
+
+/// Determine whether `path` is an incomplete write (or the metadata sidecar of one).
+fn is_partial(path: &Path) -> bool {
+    let path = match path.extension() {
+        Some(e) if e == "meta" => path.with_extension(""),
+        _ => path.to_owned(),
+    };
+
+    path.extension().is_some_and(|e| e == PARTIAL_EXTENSION)
+}
+
+/// A file that is still being written, which is removed if dropped before being published.
+///
+/// N.B: This also covers writers that are dropped without being finished or aborted, e.g. when
+/// the request mirroring the symbol is cancelled.
+struct PartialFile {
+    path: PathBuf,
+    published: bool,
+}
+
+impl PartialFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            published: false,
+        }
+    }
+
+    /// Rename the file into place at `dest`.
+    async fn publish(mut self, dest: &Path) -> std::io::Result<()> {
+        tokio::fs::rename(&self.path, dest).await?;
+        self.published = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.published {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A symbol that was found in the cache.
+pub struct CacheHit {
+    /// The length of the body, in bytes, if known.
+    pub content_length: Option<u64>,
+    /// The encoding applied to the body, if any.
+    pub content_encoding: Option<&'static str>,
+    /// The base64-encoded SHA-256 of the (decoded) body, if it was recorded when the symbol was cached.
+    pub sha256: Option<String>,
+    /// The contents of the symbol.
+    pub body: Body,
+}
+
+/// Metadata recorded alongside an entry in a filesystem cache.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FsMetadata {
+    /// The uncompressed size of the symbol, in bytes
+    pub size: u64,
+    /// The base64-encoded SHA-256 of the uncompressed symbol, if recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Append an additional extension to `path` (e.g. `foo.pdb` -> `foo.pdb.zst`).
+fn append_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+
+    PathBuf::from(path)
+}
+
+/// The path of the metadata sidecar for the filesystem cache entry at `path`.
+fn metadata_path(path: &Path) -> PathBuf {
+    append_extension(path, "meta")
+}
+
+/// Read the metadata sidecar for the filesystem cache entry at `path`, if one exists.
+async fn read_metadata(path: &Path) -> Option<FsMetadata> {
+    let data = tokio::fs::read(metadata_path(path)).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// The local filesystem tier in front of an Azure cache, if configured.
+fn local_tier(cache: &ConfigAzureCache) -> Option<ConfigFsCache> {
+    Some(ConfigFsCache {
+        path: cache.local_cache_dir.clone()?,
+        compress_cache: false,
+        write_buffer_size: None,
+        index: false,
+        min_free_bytes: None,
+    })
+}
+
+/// Determine whether there is room to mirror the symbol at `path`, of length `len` (if known),
+/// while leaving the cache's configured reserve of free space.
+pub fn has_room(cache: &ConfigCache, path: &str, len: Option<u64>) -> bool {
+    let ConfigCache::Fs(ConfigFsCache {
+        path: dir,
+        min_free_bytes: Some(reserve),
+        ..
+    }) = cache
+    else {
+        return true;
+    };
+
+    let available = match fs4::available_space(dir) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!(
+                "{:?}",
+                anyhow::Error::new(e)
+                    .context(format!("failed to query free space in {}", dir.display()))
+            );
+            return true;
+        }
+    };
+
+    let needed = len.unwrap_or(0).saturating_add(*reserve);
+    if needed > available {
+        warn!(
+            "{} has {available} bytes free, but {path} needs {needed} (including the reserve); serving it without mirroring",
+            dir.display()
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Build a client for the blob at `path` within an Azure cache.
+pub fn blob_client(
+    cache: &ConfigAzureCache,
+    token: Arc<dyn TokenCredential>,
+    path: &str,
+) -> BlobClient {
+    client_builder(cache, token).blob_client(&cache.storage_container, path)
+}
+
+/// Build a client builder for an Azure cache, authenticating with its access key if one is set.
+///
+/// The storage account is reached at its configured endpoint (with tokens for its configured
+/// scope), or in the public Azure cloud by default.
+fn client_builder(cache: &ConfigAzureCache, token: Arc<dyn TokenCredential>) -> ClientBuilder {
+    let cred = match (&cache.key, &cache.scope) {
+        (Some(key), _) => {
+            StorageCredentials::access_key(&cache.storage_account, key.expose().to_string())
+        }
+        (None, Some(scope)) => StorageCredentials::token_credential(Arc::new(
+            ScopedCredential::new(token, scope.clone()),
+        )),
+        (None, None) => StorageCredentials::token_credential(token),
+    };
+
+    match &cache.endpoint {
+        Some(endpoint) => ClientBuilder::with_location(
+            CloudLocation::Custom {
+                account: cache.storage_account.clone(),
+                uri: endpoint.as_str().trim_end_matches('/').to_string(),
+            },
+            cred,
+        ),
+        None => ClientBuilder::new(&cache.storage_account, cred),
+    }
+}
+
+/// Attempt to look up the symbol at `path` (`name1/hash/name2`) in the cache.
+///
+/// If `accept_zstd` is set, compressed entries may be returned as-is with a `zstd` content encoding.
+///
+/// Returns `Ok(None)` if the symbol is not present in the cache.
+pub async fn lookup(
+    cache: &ConfigCache,
+    token: Arc<dyn TokenCredential>,
+    path: &str,
+    accept_zstd: bool,
+) -> anyhow::Result<Option<CacheHit>> {
+    match cache {
+        ConfigCache::Azure(cache) => {
+            // Symbols are served straight from disk if they have been mirrored to the local tier.
+            if let Some(local) = local_tier(cache) {
+                if let Some(hit) = fs_lookup(&local, path, accept_zstd).await? {
+                    debug!("{path}: served from the local cache tier");
+                    return Ok(Some(hit));
+                }
+            }
+
+            let client = blob_client(cache, token, path);
+
+            if let Ok(props) = client.get_properties().await {
+                // N.B: Get the blob's data and stream it out directly instead of generating a SAS URL and returning a 302.
+                //
+                // This is important because this application may be placed behind a reverse proxy that supports auth,
+                // and returning an SAS URL subverts the authority of the reverse proxy (e.g. reverse proxy may want
+                // to log requests or set a time limit, but an SAS URL will allow users to bypass that).
+                //
+                // Blobs are downloaded as a series of ranged requests and streamed out as each one arrives, so
+                // large symbols are never held in memory in their entirety.
+                let mut get = client.get();
+                if let Some(chunk_size) = cache.chunk_size {
+                    get = get.chunk_size(chunk_size);
+                }
+
+                let body = get.into_stream().map_ok(|r| r.data).try_flatten();
+                let sha256 = props
+                    .blob
+                    .metadata
+                    .as_ref()
+                    .and_then(|m| m.get("Sha256"))
+                    .cloned();
+
+                return Ok(Some(CacheHit {
+                    content_length: Some(props.blob.properties.content_length),
+                    content_encoding: None,
+                    sha256,
+                    body: Body::from_stream(body),
+                }));
+            }
+        }
+        ConfigCache::Fs(cache) => return fs_lookup(cache, path, accept_zstd).await,
+        #[cfg(feature = "memory-cache")]
+        ConfigCache::Memory(cache) => {
+            if let Some(data) = cache.entries().lock().unwrap().get(path).cloned() {
+                return Ok(Some(CacheHit {
+                    content_length: Some(data.len() as u64),
+                    content_encoding: None,
+                    sha256: None,
+                    body: Body::from(data),
+                }));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Attempt to look up the symbol at `path` in a filesystem cache. See [`lookup`].
+async fn fs_lookup(
+    cache: &ConfigFsCache,
+    path: &str,
+    accept_zstd: bool,
+) -> anyhow::Result<Option<CacheHit>> {
+    let path = cache.path.join(path);
+    if let Ok(f) = tokio::fs::File::open(&path).await {
+        let meta = f.metadata().await.context("failed to get file metadata")?;
+
+        return Ok(Some(CacheHit {
+            content_length: Some(meta.len()),
+            content_encoding: None,
+            sha256: read_metadata(&path).await.and_then(|m| m.sha256),
+            body: Body::from_stream(ReaderStream::new(f)),
+        }));
+    }
+
+    // Fall back to a compressed copy of the symbol, if one exists.
+    let compressed = append_extension(&path, COMPRESSED_EXTENSION);
+    if let Ok(f) = tokio::fs::File::open(&compressed).await {
+        // Serve the compressed data directly if the client can decode it.
+        if accept_zstd {
+            let meta = f.metadata().await.context("failed to get file metadata")?;
+
+            return Ok(Some(CacheHit {
+                content_length: Some(meta.len()),
+                content_encoding: Some("zstd"),
+                sha256: None,
+                body: Body::from_stream(ReaderStream::new(f)),
+            }));
+        }
+
+        // Otherwise, decompress on the fly. The uncompressed size is recorded in the metadata sidecar.
+        let meta = read_metadata(&path).await;
+        let decoder = ZstdDecoder::new(BufReader::new(f));
+
+        return Ok(Some(CacheHit {
+            content_length: meta.as_ref().map(|m| m.size),
+            content_encoding: None,
+            sha256: meta.and_then(|m| m.sha256),
+            body: Body::from_stream(ReaderStream::new(decoder)),
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Append `chunk` to `buffer`, returning the buffered data as a block once it reaches
+/// `block_size` bytes.
+///
+/// N.B: Upstream chunks are typically tiny, and a blob may consist of at most 50,000 blocks.
+/// Accumulate chunks into larger blocks so that large symbols do not exhaust that limit.
+fn buffer_block(buffer: &mut Vec<u8>, chunk: &[u8], block_size: usize) -> Option<Vec<u8>> {
+    buffer.extend_from_slice(chunk);
+
+    (buffer.len() >= block_size).then(|| std::mem::replace(buffer, Vec::with_capacity(block_size)))
+}
+
+/// Upload `data` as a new block of the blob, appending it to `block_list`.
+async fn put_block(
+    client: &BlobClient,
+    block_list: &mut BlockList,
+    data: Bytes,
+) -> anyhow::Result<()> {
+    // N.B: `block_id` must be <= 64 bytes in size.
+    // Use a randomly generated ID to avoid conflicts.
+    let block_id = format!("{}", Uuid::new_v4());
+
+    client
+        .put_block(block_id.clone(), data)
+        .await
+        .context("failed to put block")?;
+
+    block_list
+        .blocks
+        .push(BlobBlockType::new_uncommitted(block_id));
+
+    Ok(())
+}
+
+/// Writes a symbol into the cache as it is streamed from an upstream server.
+pub enum CacheWriter {
+    Azure {
+        client: BlobClient,
+        block_list: BlockList,
+        /// Data not yet uploaded as a block
+        buffer: Vec<u8>,
+        /// The size of each uploaded block
+        block_size: usize,
+        /// The writer mirroring the symbol into the local tier, if configured
+        local: Option<Box<CacheWriter>>,
+    },
+    Fs {
+        /// The path of the symbol within the cache
+        path: PathBuf,
+        /// The path of the finished file, which differs from `path` if compressed
+        file_path: PathBuf,
+        file: Box<dyn AsyncWrite + Send + Unpin>,
+        /// The file being written, which is renamed to `file_path` once finished
+        ///
+        /// N.B: This is declared after `file` so that the file is closed before it is removed.
+        partial: PartialFile,
+        /// Whether the file is compressed
+        compressed: bool,
+        /// The number of (uncompressed) bytes written so far
+        written: u64,
+    },
+    #[cfg(feature = "memory-cache")]
+    Memory {
+        cache: ConfigMemoryCache,
+        /// The path of the symbol within the cache
+        path: String,
+        buffer: Vec<u8>,
+    },
+}
+
+impl CacheWriter {
+    /// Begin writing the symbol at `path` (`name1/hash/name2`) into the cache.
+    pub async fn create(
+        cache: &ConfigCache,
+        token: Arc<dyn TokenCredential>,
+        path: &str,
+    ) -> anyhow::Result<Self> {
+        match cache {
+            ConfigCache::Azure(cache) => {
+                let block_size = cache.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+
+                // N.B: The local tier is only an accelerator, so failing to write to it must not
+                // prevent the symbol from being mirrored to Azure.
+                let local = match local_tier(cache) {
+                    Some(local) => match Self::create_fs(&local, path).await {
+                        Ok(writer) => Some(Box::new(writer)),
+                        Err(e) => {
+                            warn!(
+                                "{:?}",
+                                e.context(format!(
+                                    "failed to mirror {path} to the local cache tier"
+                                ))
+                            );
+                            None
+                        }
+                    },
+                    None => None,
+                };
+
+                Ok(Self::Azure {
+                    client: blob_client(cache, token, path),
+                    block_list: BlockList::default(),
+                    buffer: Vec::with_capacity(block_size),
+                    block_size,
+                    local,
+                })
+            }
+            ConfigCache::Fs(cache) => Self::create_fs(cache, path).await,
+            #[cfg(feature = "memory-cache")]
+            ConfigCache::Memory(cache) => Ok(Self::Memory {
+                cache: cache.clone(),
+                path: path.to_string(),
+                buffer: Vec::new(),
+            }),
+        }
+    }
+
+    /// Begin writing the symbol at `path` into a filesystem cache.
+    async fn create_fs(cache: &ConfigFsCache, path: &str) -> anyhow::Result<Self> {
+        let path = cache.path.join(path);
+
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .context("failed to create cache directory")?;
+
+        let file_path = if cache.compress_cache {
+            append_extension(&path, COMPRESSED_EXTENSION)
+        } else {
+            path.clone()
+        };
+
+        // N.B: The same symbol may be mirrored by several requests at once, which must not write
+        // to the same file.
+        let partial_path = append_extension(
+            &file_path,
+            &format!("{}.{PARTIAL_EXTENSION}", Uuid::new_v4().simple()),
+        );
+        let file = tokio::fs::File::create(&partial_path)
+            .await
+            .context("failed to create cache file")?;
+        let partial = PartialFile::new(partial_path);
+
+        // N.B: Upstream chunks are typically small. Batch them into larger writes to reduce
+        // syscall overhead for large symbols.
+        let file = BufWriter::with_capacity(
+            cache.write_buffer_size.unwrap_or(DEFAULT_WRITE_BUFFER_SIZE),
+            file,
+        );
+        let file: Box<dyn AsyncWrite + Send + Unpin> = if cache.compress_cache {
+            Box::new(ZstdEncoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        Ok(Self::Fs {
+            path,
+            file_path,
+            file,
+            partial,
+            compressed: cache.compress_cache,
+            written: 0,
+        })
+    }
+
+    /// Write the next chunk of the symbol.
+    pub async fn write(&mut self, chunk: Bytes) -> anyhow::Result<()> {
+        match self {
+            Self::Azure {
+                client,
+                block_list,
+                buffer,
+                block_size,
+                local,
+            } => {
+                let failed = match local {
+                    Some(w) => Box::pin(w.write(chunk.clone())).await.err(),
+                    None => None,
+                };
+                if let Some(e) = failed {
+                    warn!(
+                        "{:?}",
+                        e.context("failed to write chunk to the local cache tier")
+                    );
+
+                    if let Some(w) = local.take() {
+                        Box::pin(w.abort()).await;
+                    }
+                }
+
+                if let Some(block) = buffer_block(buffer, &chunk, *block_size) {
+                    put_block(client, block_list, block.into()).await?;
+                }
+            }
+            Self::Fs { file, written, .. } => {
+                file.write_all(&chunk)
+                    .await
+                    .context("failed to write chunk")?;
+
+                *written += chunk.len() as u64;
+            }
+            #[cfg(feature = "memory-cache")]
+            Self::Memory { buffer, .. } => buffer.extend_from_slice(&chunk),
+        }
+
+        Ok(())
+    }
+
+    /// Commit the symbol to the cache, recording the upstream URL it was fetched from and its
+    /// base64-encoded SHA-256 (if computed).
+    pub async fn finish(self, upstream: &Url, sha256: Option<String>) -> anyhow::Result<()> {
+        match self {
+            Self::Azure {
+                client,
+                mut block_list,
+                buffer,
+                local,
+                ..
+            } => {
+                if let Some(w) = local {
+                    if let Err(e) = Box::pin(w.finish(upstream, sha256.clone())).await {
+                        warn!(
+                            "{:?}",
+                            e.context("failed to mirror symbol to the local cache tier")
+                        );
+                    }
+                }
+
+                // Upload any remaining data.
+                if !buffer.is_empty() {
+                    put_block(&client, &mut block_list, buffer.into()).await?;
+                }
+
+                // N.B: If multiple instances of this server attempt to upload the same blob at the same
+                // time, the last one wins. Unfortunately we cannot acquire a lease on a blob that has not
+                // been created so we cannot prevent this race.
+                let mut meta = Metadata::new();
+                meta.insert(
+                    "UpstreamServer",
+                    form_urlencoded::byte_serialize(upstream.as_str().as_bytes())
+                        .collect::<String>(),
+                );
+                if let Some(sha256) = sha256 {
+                    meta.insert("Sha256", sha256);
+                }
+
+                // N.B: Committing a block list is idempotent (the blob is keyed by the symbol's path),
+                // so it is safe to retry. The blocks themselves have already been uploaded and are
+                // reused by every attempt.
+                let mut attempt = 1;
+                loop {
+                    match client
+                        .put_block_list(block_list.clone())
+                        .metadata(meta.clone())
+                        .await
+                    {
+                        Ok(_) => break,
+                        Err(e) if attempt < COMMIT_ATTEMPTS => {
+                            let delay = Duration::from_secs(1 << (attempt - 1));
+                            warn!(
+                                "{:?}",
+                                anyhow::Error::new(e).context(format!(
+                                    "failed to put block list (attempt {attempt}/{COMMIT_ATTEMPTS}), retrying in {}s",
+                                    delay.as_secs()
+                                ))
+                            );
+
+                            tokio::time::sleep(delay).await;
+                            attempt += 1;
+                        }
+                        Err(e) => {
+                            return Err(anyhow::Error::new(e).context(format!(
+                                "failed to put block list after {COMMIT_ATTEMPTS} attempts"
+                            )))
+                        }
+                    }
+                }
+            }
+            Self::Fs {
+                path,
+                file_path,
+                mut file,
+                partial,
+                compressed,
+                written,
+            } => {
+                // N.B: Shutting down the writer flushes it and finalizes the compressed stream, if
+                // any. On failure, the partial files are removed as they are dropped.
+                file.shutdown()
+                    .await
+                    .context("failed to finalize cache file")?;
+                drop(file);
+
+                // Record the uncompressed size of compressed entries so that they can later be
+                // served with an accurate `Content-Length`. The sidecar is also written under a
+                // unique name, so that concurrent writers cannot interleave their metadata.
+                let meta = if compressed || sha256.is_some() {
+                    let data = serde_json::to_vec(&FsMetadata {
+                        size: written,
+                        sha256,
+                    })
+                    .context("failed to serialize metadata")?;
+
+                    let meta = PartialFile::new(metadata_path(&partial.path));
+                    tokio::fs::write(&meta.path, data)
+                        .await
+                        .context("failed to write metadata")?;
+                    Some(meta)
+                } else {
+                    None
+                };
+
+                partial
+                    .publish(&file_path)
+                    .await
+                    .context("failed to publish cache file")?;
+
+                if let Some(meta) = meta {
+                    meta.publish(&metadata_path(&path))
+                        .await
+                        .context("failed to publish metadata")?;
+                }
+            }
+            #[cfg(feature = "memory-cache")]
+            Self::Memory {
+                cache,
+                path,
+                buffer,
+            } => {
+                cache.entries().lock().unwrap().put(path, buffer.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Abandon the write, discarding anything written so far.
+    pub async fn abort(self) {
+        match self {
+            // N.B: Uncommitted blocks are garbage collected by Azure, so only the local tier needs
+            // to be cleaned up.
+            Self::Azure { local, .. } => {
+                if let Some(w) = local {
+                    Box::pin(w.abort()).await;
+                }
+            }
+            // N.B: The partial file is removed as it is dropped.
+            Self::Fs { .. } => {}
+            #[cfg(feature = "memory-cache")]
+            Self::Memory { .. } => {}
+        }
+    }
+}
+
+/// An entry in a filesystem cache that failed verification.
+#[derive(Serialize, Debug)]
+pub struct CorruptEntry {
+    /// The path of the entry, relative to the cache directory
+    pub path: PathBuf,
+    /// Why the entry is considered corrupt
+    pub reason: String,
+}
+
+/// The results of verifying a filesystem cache.
+#[derive(Serialize, Debug, Default)]
+pub struct VerifyReport {
+    /// The number of entries scanned
+    pub scanned: u64,
+    /// Entries that failed verification
+    pub corrupt: Vec<CorruptEntry>,
+    /// The number of corrupt entries removed
+    pub removed: u64,
+}
+
+/// Walk a filesystem cache, checking that each entry begins with the signature expected for its
+/// file type and (if compressed) decompresses to the size recorded in its metadata.
+///
+/// If `remove` is set, corrupt entries are deleted.
+pub async fn verify(
+    cache: &ConfigFsCache,
+    index: Option<&FsIndex>,
+    signatures: &std::collections::HashMap<String, String>,
+    remove: bool,
+) -> anyhow::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+
+    for path in entry_files(&cache.path).await? {
+        report.scanned += 1;
+
+        let Err(reason) = verify_entry(&path, signatures).await else {
+            continue;
+        };
+
+        if remove {
+            let (logical, _) = logical_path(&path);
+            let _ = tokio::fs::remove_file(&path).await;
+            let _ = tokio::fs::remove_file(metadata_path(&logical)).await;
+            report.removed += 1;
+
+            if let Some(index) = index {
+                index.remove(&entry_key(&cache.path, &logical));
+            }
+        }
+
+        report.corrupt.push(CorruptEntry {
+            path: path.strip_prefix(&cache.path).unwrap_or(&path).to_owned(),
+            reason,
+        });
+    }
+
+    Ok(report)
+}
+
+/// How long an incomplete write must have gone untouched before it is swept away, as newer ones
+/// may still be in progress in other processes sharing the cache.
+pub const STALE_PARTIAL_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Remove the incomplete writes left in a filesystem cache (or the local tier of an Azure cache)
+/// by processes that exited mid-write, returning the number removed.
+pub async fn sweep_partial(cache: &ConfigCache, min_age: Duration) -> anyhow::Result<u64> {
+    let cache = match cache {
+        ConfigCache::Fs(cache) => cache.clone(),
+        ConfigCache::Azure(cache) => match local_tier(cache) {
+            Some(local) => local,
+            None => return Ok(0),
+        },
+        #[cfg(feature = "memory-cache")]
+        ConfigCache::Memory(_) => return Ok(0),
+    };
+
+    // A cache that has never been written to is simply empty.
+    if !tokio::fs::try_exists(&cache.path).await.unwrap_or(false) {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for path in list_files(&cache.path, is_partial).await? {
+        let stale = tokio::fs::metadata(&path)
+            .await
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age >= min_age));
+
+        if stale && tokio::fs::remove_file(&path).await.is_ok() {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Recursively list the files holding entries of the filesystem cache rooted at `root`.
+///
+/// Metadata sidecars are omitted, as they describe the entry stored alongside them.
+async fn entry_files(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    list_files(root, is_entry).await
+}
+
+/// Recursively list the files under `root` that satisfy `filter`.
+async fn list_files(root: &Path, filter: fn(&Path) -> bool) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("failed to read {}", dir.display()))?;
+
+        while let Some(entry) = entries.next_entry().await.context("failed to read entry")? {
+            let path = entry.path();
+            let file_type = entry.file_type().await.context("failed to get file type")?;
+
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if filter(&path) {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// The cache key of the symbol stored at `path` in the filesystem cache rooted at `root`.
+fn entry_key(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// An in-memory index of the symbols held in a filesystem cache.
+///
+/// N.B: The index only tracks entries written by this process, so it must not be used with a
+/// cache directory that is shared with other writers.
+#[derive(Debug, Default)]
+pub struct FsIndex {
+    keys: RwLock<HashSet<String>>,
+}
+
+impl FsIndex {
+    /// Build an index of the entries currently present in `cache`.
+    pub async fn build(cache: &ConfigFsCache) -> anyhow::Result<Self> {
+        // A cache that has never been written to is simply empty.
+        if !tokio::fs::try_exists(&cache.path).await.unwrap_or(false) {
+            return Ok(Self::default());
+        }
+
+        let keys = entry_files(&cache.path)
+            .await?
+            .iter()
+            .map(|file| entry_key(&cache.path, &logical_path(file).0))
+            .collect();
+
+        Ok(Self {
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// The number of symbols in the index.
+    pub fn count(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    /// Determine whether the symbol at `path` may be cached.
+    pub fn contains(&self, path: &str) -> bool {
+        self.keys.read().unwrap().contains(path)
+    }
+
+    pub fn insert(&self, path: String) {
+        self.keys.write().unwrap().insert(path);
+    }
+
+    pub fn remove(&self, path: &str) {
+        self.keys.write().unwrap().remove(path);
+    }
+}
+
+/// Determine the path of the symbol stored at `path` in a filesystem cache, and whether it is
+/// stored compressed.
+fn logical_path(path: &Path) -> (PathBuf, bool) {
+    match path.extension() {
+        Some(ext) if ext == COMPRESSED_EXTENSION => (path.with_extension(""), true),
+        _ => (path.to_owned(), false),
+    }
+}
+
+/// Verify a single filesystem cache entry, returning the reason it is corrupt if so.
+async fn verify_entry(
+    path: &Path,
+    signatures: &std::collections::HashMap<String, String>,
+) -> Result<(), String> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("failed to open: {e}"))?;
+
+    let (logical, compressed) = logical_path(path);
+    let name = logical
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut reader: Box<dyn AsyncRead + Send + Unpin> = if compressed {
+        Box::new(ZstdDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    };
+
+    if let Some(signature) = crate::expected_signature(&name, signatures) {
+        let mut prefix = vec![0; signature.len()];
+        reader
+            .read_exact(&mut prefix)
+            .await
+            .map_err(|_| "too short to contain a signature".to_string())?;
+
+        if prefix != signature {
+            return Err("does not have the expected signature".into());
+        }
+    }
+
+    // Compressed entries must decompress cleanly to their recorded size.
+    if compressed {
+        let signature_len = crate::expected_signature(&name, signatures).map_or(0, |s| s.len());
+        let remaining = tokio::io::copy(&mut reader, &mut tokio::io::sink())
+            .await
+            .map_err(|e| format!("failed to decompress: {e}"))?;
+
+        if let Some(meta) = read_metadata(&logical).await {
+            let size = remaining + signature_len as u64;
+            if size != meta.size {
+                return Err(format!(
+                    "decompressed to {size} bytes, but {} were recorded",
+                    meta.size
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// An entry listed in a cache manifest.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestEntry {
+    /// The path of the symbol (`name1/hash/name2`)
+    pub path: String,
+    /// The (uncompressed) size of the symbol, in bytes, if known
+    pub size: Option<u64>,
+    /// The upstream URL the symbol was fetched from, if recorded
+    pub upstream: Option<String>,
+}
+
+/// List every entry in the cache.
+pub async fn list(
+    cache: &ConfigCache,
+    token: Arc<dyn TokenCredential>,
+) -> anyhow::Result<Vec<ManifestEntry>> {
+    let mut manifest = Vec::new();
+
+    match cache {
+        ConfigCache::Azure(cache) => {
+            let client = client_builder(cache, token).container_client(&cache.storage_container);
+            let mut pages = client.list_blobs().include_metadata(true).into_stream();
+
+            while let Some(page) = pages.next().await {
+                let page = page.context("failed to list blobs")?;
+
+                for blob in page.blobs.blobs() {
+                    let upstream = blob
+                        .metadata
+                        .as_ref()
+                        .and_then(|m| m.get("UpstreamServer"))
+                        // N.B: The URL is form-encoded when written, so it has no unescaped `=` or `&`
+                        // and decodes as a single key.
+                        .and_then(|u| form_urlencoded::parse(u.as_bytes()).next())
+                        .map(|(u, _)| u.into_owned());
+
+                    manifest.push(ManifestEntry {
+                        path: blob.name.clone(),
+                        size: Some(blob.properties.content_length),
+                        upstream,
+                    });
+                }
+            }
+        }
+        ConfigCache::Fs(cache) => {
+            for file in entry_files(&cache.path).await? {
+                let (path, _) = logical_path(&file);
+                let Some(size) = entry_size(&path).await else {
+                    continue;
+                };
+
+                manifest.push(ManifestEntry {
+                    path: entry_key(&cache.path, &path),
+                    size,
+                    upstream: None,
+                });
+            }
+        }
+        #[cfg(feature = "memory-cache")]
+        ConfigCache::Memory(cache) => {
+            for (path, data) in cache.entries().lock().unwrap().iter() {
+                manifest.push(ManifestEntry {
+                    path: path.clone(),
+                    size: Some(data.len() as u64),
+                    upstream: None,
+                });
+            }
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// An entry in a listing of a filesystem cache.
+#[derive(Serialize, Debug)]
+pub struct ListedEntry {
+    /// The path of the symbol (`name1/hash/name2`)
+    pub path: String,
+    /// The (uncompressed) size of the symbol, in bytes, if known
+    pub size: Option<u64>,
+    /// When the entry was last written, in seconds since the Unix epoch
+    pub modified: Option<u64>,
+}
+
+/// Compare two cache keys component by component, which is the order `walk` visits them in.
+fn key_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    a.split('/').cmp(b.split('/'))
+}
+
+/// Read the children of `dir`, sorted by the cache key they correspond to.
+async fn sorted_children(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+
+    let mut children = Vec::new();
+    while let Some(entry) = entries.next_entry().await.context("failed to read entry")? {
+        children.push(entry.path());
+    }
+
+    children.sort_by_cached_key(|path| logical_path(path).0);
+    Ok(children)
+}
+
+/// Walk the entries of the filesystem cache in key order, starting after the entry at `after`.
+///
+/// Only a single directory is read at a time (and directories entirely before `after` are
+/// skipped), so the cache is never listed in its entirety up front.
+pub fn walk(
+    cache: &ConfigFsCache,
+    after: Option<String>,
+) -> impl Stream<Item = anyhow::Result<ListedEntry>> + Send + 'static {
+    let root = cache.path.clone();
+    let state = (root.clone(), after, vec![vec![root].into_iter()]);
+
+    futures::stream::unfold(state, |(root, after, mut stack)| async move {
+        loop {
+            let Some(path) = stack.last_mut()?.next() else {
+                stack.pop();
+                continue;
+            };
+
+            let Ok(meta) = tokio::fs::metadata(&path).await else {
+                // A cache that has never been written to is simply empty, and entries may be
+                // removed while they are being listed.
+                continue;
+            };
+
+            if meta.is_dir() {
+                let key = entry_key(&root, &path);
+                let skip = path != root
+                    && after.as_ref().is_some_and(|after| {
+                        !after.starts_with(&format!("{key}/")) && key_cmp(&key, after).is_lt()
+                    });
+                if skip {
+                    continue;
+                }
+
+                match sorted_children(&path).await {
+                    Ok(children) => stack.push(children.into_iter()),
+                    Err(e) => return Some((Err(e), (root, after, stack))),
+                }
+
+                continue;
+            }
+
+            if !is_entry(&path) {
+                continue;
+            }
+
+            let (logical, compressed) = logical_path(&path);
+            let key = entry_key(&root, &logical);
+            if after
+                .as_ref()
+                .is_some_and(|after| key_cmp(&key, after).is_le())
+            {
+                continue;
+            }
+
+            let size = if compressed {
+                read_metadata(&logical).await.map(|m| m.size)
+            } else {
+                Some(meta.len())
+            };
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+
+            let entry = ListedEntry {
+                path: key,
+                size,
+                modified,
+            };
+            return Some((Ok(entry), (root, after, stack)));
+        }
+    })
+}
+
+/// Determine the (uncompressed) size of the filesystem cache entry for the symbol at `path`.
+///
+/// Returns `None` if there is no such entry, or `Some(None)` if its size is unknown.
+async fn entry_size(path: &Path) -> Option<Option<u64>> {
+    if let Ok(meta) = tokio::fs::metadata(path).await {
+        return Some(Some(meta.len()));
+    }
+
+    tokio::fs::metadata(append_extension(path, COMPRESSED_EXTENSION))
+        .await
+        .ok()?;
+
+    Some(read_metadata(path).await.map(|m| m.size))
+}
+
+/// Determine the (uncompressed) size of the symbol at `path` (`name1/hash/name2`) in the cache.
+///
+/// Returns `None` if the symbol is not present in the cache, or `Some(None)` if its size is unknown.
+pub async fn size(
+    cache: &ConfigCache,
+    token: Arc<dyn TokenCredential>,
+    path: &str,
+) -> anyhow::Result<Option<Option<u64>>> {
+    match cache {
+        ConfigCache::Azure(cache) => {
+            if let Some(local) = local_tier(cache) {
+                if let Some(size) = entry_size(&local.path.join(path)).await {
+                    return Ok(Some(size));
+                }
+            }
+
+            Ok(blob_client(cache, token, path)
+                .get_properties()
+                .await
+                .ok()
+                .map(|props| Some(props.blob.properties.content_length)))
+        }
+        ConfigCache::Fs(cache) => Ok(entry_size(&cache.path.join(path)).await),
+        #[cfg(feature = "memory-cache")]
+        ConfigCache::Memory(cache) => Ok(cache
+            .entries()
+            .lock()
+            .unwrap()
+            .peek(path)
+            .map(|data| Some(data.len() as u64))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn fs_cache(path: &Path) -> ConfigFsCache {
+        ConfigFsCache {
+            path: path.to_owned(),
+            compress_cache: false,
+            write_buffer_size: None,
+            index: false,
+            min_free_bytes: None,
+        }
+    }
+
+    fn upstream() -> Url {
+        Url::parse("https://symbols.example.com/").unwrap()
+    }
+
+    /// Split `len` bytes, arriving in upstream-sized chunks, into blocks as an Azure writer would.
+    fn split_blocks(len: usize) -> Vec<Vec<u8>> {
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+        let mut buffer = Vec::new();
+        let mut blocks = Vec::new();
+        for chunk in data.chunks(16 * 1024) {
+            blocks.extend(buffer_block(&mut buffer, chunk, DEFAULT_BLOCK_SIZE));
+        }
+        if !buffer.is_empty() {
+            blocks.push(buffer);
+        }
+
+        assert_eq!(blocks.concat(), data);
+        blocks
+    }
+
+    #[test]
+    fn blocks_below_32_mib() {
+        let blocks = split_blocks(32 * MIB - 1);
+        assert_eq!(blocks.len(), 8);
+        assert_eq!(blocks.last().unwrap().len(), DEFAULT_BLOCK_SIZE - 1);
+    }
+
+    #[test]
+    fn blocks_at_32_mib() {
+        let blocks = split_blocks(32 * MIB);
+        assert_eq!(blocks.len(), 8);
+        assert!(blocks.iter().all(|b| b.len() == DEFAULT_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn blocks_above_32_mib() {
+        let blocks = split_blocks(32 * MIB + 1);
+        assert_eq!(blocks.len(), 9);
+        assert_eq!(blocks.last().unwrap().len(), 1);
+    }
+
+    /// Check that batching writes speeds up mirroring a large symbol received in small upstream
+    /// chunks.
+    ///
+    /// Run with `cargo test --release -- --ignored write_buffer_throughput`.
+    #[tokio::test]
+    #[ignore]
+    async fn write_buffer_throughput() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunk = Bytes::from(vec![0xaa; 1024]);
+
+        // N.B: A 1-byte buffer is bypassed by every write, so each chunk is its own syscall.
+        let mut elapsed = Vec::new();
+        for buffer in [1, DEFAULT_WRITE_BUFFER_SIZE] {
+            let mut cache = fs_cache(dir.path());
+            cache.write_buffer_size = Some(buffer);
+
+            let start = std::time::Instant::now();
+            let path = format!("bench.pdb/{buffer}/bench.pdb");
+            let mut writer = CacheWriter::create_fs(&cache, &path).await.unwrap();
+            for _ in 0..64 * 1024 {
+                writer.write(chunk.clone()).await.unwrap();
+            }
+            writer.finish(&upstream(), None).await.unwrap();
+
+            elapsed.push(start.elapsed());
+        }
+
+        assert!(
+            elapsed[1] < elapsed[0],
+            "batched writes took {:?}, unbatched writes took {:?}",
+            elapsed[1],
+            elapsed[0]
+        );
+    }
+
+    /// A credential for caches that never authenticate.
+    #[derive(Debug)]
+    struct NoCredential;
+
+    #[async_trait::async_trait]
+    impl TokenCredential for NoCredential {
+        async fn get_token(&self, _: &[&str]) -> azure_core::Result<azure_core::auth::AccessToken> {
+            Err(azure_core::error::Error::with_message(
+                azure_core::error::ErrorKind::Credential,
+                || "no credential",
+            ))
+        }
+
+        async fn clear_cache(&self) -> azure_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn token() -> Arc<dyn TokenCredential> {
+        Arc::new(NoCredential)
+    }
+
+    /// Write `data` to `cache` in small chunks, as a mirror would.
+    async fn mirror(cache: &ConfigCache, path: &str, data: &[u8]) -> CacheWriter {
+        let mut writer = CacheWriter::create(cache, token(), path).await.unwrap();
+        for chunk in data.chunks(7) {
+            writer.write(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+
+        writer
+    }
+
+    async fn read(cache: &ConfigCache, path: &str) -> Option<Bytes> {
+        let hit = lookup(cache, token(), path, false).await.unwrap()?;
+        Some(axum::body::to_bytes(hit.body, usize::MAX).await.unwrap())
+    }
+
+    #[cfg(feature = "memory-cache")]
+    #[tokio::test]
+    async fn memory_round_trip() {
+        let cache = ConfigCache::Memory(ConfigMemoryCache::default());
+        let path = "foo.pdb/0123456789ABCDEF1/foo.pdb";
+
+        let writer = mirror(&cache, path, b"Microsoft C/C++ MSF 7.00").await;
+        assert!(read(&cache, path).await.is_none());
+
+        writer.finish(&upstream(), None).await.unwrap();
+        assert_eq!(
+            read(&cache, path).await.as_deref(),
+            Some(&b"Microsoft C/C++ MSF 7.00"[..])
+        );
+        assert_eq!(size(&cache, token(), path).await.unwrap(), Some(Some(24)));
+    }
+
+    #[cfg(feature = "memory-cache")]
+    #[tokio::test]
+    async fn memory_abort() {
+        let cache = ConfigCache::Memory(ConfigMemoryCache::default());
+        let path = "foo.pdb/0123456789ABCDEF1/foo.pdb";
+
+        mirror(&cache, path, b"Microsoft C/C++ MSF 7.00")
+            .await
+            .abort()
+            .await;
+        assert!(read(&cache, path).await.is_none());
+        assert_eq!(size(&cache, token(), path).await.unwrap(), None);
+    }
+
+    /// Symbols are mirrored as their chunks arrive, so their length need not be known up front
+    /// (e.g. for chunked responses without a `Content-Length`).
+    #[tokio::test]
+    async fn fs_mirror_without_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = "foo.pdb/0123456789ABCDEF1/foo.pdb";
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+
+        for compress in [false, true] {
+            let mut fs = fs_cache(dir.path());
+            fs.compress_cache = compress;
+            let cache = ConfigCache::Fs(fs);
+
+            mirror(&cache, path, &data)
+                .await
+                .finish(&upstream(), None)
+                .await
+                .unwrap();
+
+            assert_eq!(read(&cache, path).await.as_deref(), Some(&data[..]));
+            assert_eq!(
+                size(&cache, token(), path).await.unwrap(),
+                Some(Some(data.len() as u64))
+            );
+
+            tokio::fs::remove_dir_all(dir.path().join("foo.pdb"))
+                .await
+                .unwrap();
+        }
+    }
+
+    /// The names of all files under `dir`, relative to it.
+    async fn files(dir: &Path) -> Vec<String> {
+        let mut files = list_files(dir, |_| true)
+            .await
+            .unwrap()
+            .iter()
+            .map(|f| entry_key(dir, f))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn fs_publish() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = fs_cache(dir.path());
+        fs.compress_cache = true;
+        let cache = ConfigCache::Fs(fs);
+        let path = "foo.pdb/0123456789ABCDEF1/foo.pdb";
+
+        mirror(&cache, path, b"Microsoft C/C++ MSF 7.00")
+            .await
+            .finish(&upstream(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            files(dir.path()).await,
+            [format!("{path}.meta"), format!("{path}.zst")]
+        );
+    }
+
+    #[tokio::test]
+    async fn fs_abort() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ConfigCache::Fs(fs_cache(dir.path()));
+        let path = "foo.pdb/0123456789ABCDEF1/foo.pdb";
+
+        mirror(&cache, path, b"Microsoft C/C++ MSF 7.00")
+            .await
+            .abort()
+            .await;
+        assert!(files(dir.path()).await.is_empty());
+        assert!(read(&cache, path).await.is_none());
+
+        // Writers that are simply dropped (e.g. by a cancelled request) clean up after themselves.
+        drop(mirror(&cache, path, b"Microsoft C/C++ MSF 7.00").await);
+        assert!(files(dir.path()).await.is_empty());
+        assert!(read(&cache, path).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sweep_stale_partials() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ConfigCache::Fs(fs_cache(dir.path()));
+        let path = "foo.pdb/0123456789ABCDEF1/foo.pdb";
+
+        mirror(&cache, path, b"Microsoft C/C++ MSF 7.00")
+            .await
+            .finish(&upstream(), None)
+            .await
+            .unwrap();
+
+        // Simulate the files left behind by a process that exited mid-write.
+        let entry = dir.path().join(path);
+        let partial = append_extension(&entry, "0123.partial");
+        std::fs::write(&partial, b"Microsoft").unwrap();
+        std::fs::write(metadata_path(&partial), b"{}").unwrap();
+
+        // Recent writes may still be in progress.
+        assert_eq!(sweep_partial(&cache, STALE_PARTIAL_AGE).await.unwrap(), 0);
+        assert_eq!(files(dir.path()).await.len(), 3);
+
+        assert_eq!(sweep_partial(&cache, Duration::ZERO).await.unwrap(), 2);
+        assert_eq!(files(dir.path()).await, [path]);
+    }
+}
@@ -0,0 +1,65 @@
+//! Coalescing of concurrent requests for the same symbol onto a single upstream download.
+//!
+//! The first request to miss the cache for a symbol becomes its leader, and downloads and mirrors
+//! it as usual. Requests arriving in the meantime wait for the leader to finish, and are then
+//! served from the cache.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+/// The symbols currently being downloaded, keyed by cache path.
+#[derive(Default)]
+pub struct Flights(Mutex<HashMap<String, watch::Receiver<()>>>);
+
+/// The role of a request in fetching a symbol. See [`Flights::join`].
+pub enum Flight {
+    /// The request must download the symbol itself. Followers are released once the guard is
+    /// dropped.
+    Leader(FlightGuard),
+    /// Another request is already downloading the symbol. Wait for it with [`wait`].
+    Follower(watch::Receiver<()>),
+}
+
+impl Flights {
+    /// Join the download of the symbol at `path`, becoming its leader if there is none.
+    pub fn join(self: &Arc<Self>, path: &str) -> Flight {
+        let mut flights = self.0.lock().unwrap();
+        if let Some(done) = flights.get(path) {
+            return Flight::Follower(done.clone());
+        }
+
+        let (tx, rx) = watch::channel(());
+        flights.insert(path.to_string(), rx);
+
+        Flight::Leader(FlightGuard {
+            flights: self.clone(),
+            path: path.to_string(),
+            _done: tx,
+        })
+    }
+}
+
+/// Wait for the leader of a download to finish, successfully or otherwise.
+pub async fn wait(mut done: watch::Receiver<()>) {
+    // N.B: Nothing is ever sent, so this only returns once the leader's guard is dropped.
+    while done.changed().await.is_ok() {}
+}
+
+/// Held by the leader of a download until it has finished mirroring the symbol.
+pub struct FlightGuard {
+    flights: Arc<Flights>,
+    path: String,
+    /// Dropped along with the guard, releasing any followers
+    _done: watch::Sender<()>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        // N.B: The entry is removed before the sender is dropped, so that followers released by
+        // it never find a finished download.
+        self.flights.0.lock().unwrap().remove(&self.path);
+    }
+}
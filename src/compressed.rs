@@ -0,0 +1,257 @@
+//! The fallbacks used by classic symbol stores for symbols they do not host directly.
+//!
+//! `symstore` may store a symbol compressed as a cabinet with the last character of its name
+//! replaced by an underscore (e.g. `foo.pd_`), or store a `file.ptr` pointing at the symbol's
+//! actual location.
+use std::io::{Cursor, Read};
+
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    response::Response,
+};
+use reqwest::header;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    content_type, encode_digest, host_allowed, mirror_complete, send_request, status::Stats,
+    symbol_url, upstream_request, AppState, ConfigCache, REPR_DIGEST, UPSTREAM_SERVER,
+    UPSTREAM_SOURCE, X_CACHE,
+};
+
+/// The name of the pointer file stored in place of a symbol.
+const FILE_PTR: &str = "file.ptr";
+
+/// The name a symbol is stored under when compressed (e.g. `foo.pdb` -> `foo.pd_`).
+fn compressed_name(name: &str) -> Option<String> {
+    let mut chars = name.chars();
+    match chars.next_back()? {
+        '_' => None,
+        _ => Some(format!("{}_", chars.as_str())),
+    }
+}
+
+/// Resolve the contents of a `file.ptr` stored on the server at `base`, returning the URL of the
+/// symbol it points to, or the reason it cannot be fetched.
+fn parse_pointer(base: &Url, body: &str) -> Result<Url, String> {
+    // The pointer holds either `PATH:<location>` or `MSG:<reason the symbol is unavailable>`.
+    let body = body.trim();
+    let Some(target) = body.strip_prefix("PATH:") else {
+        return Err(body.to_string());
+    };
+
+    // Locations are usually relative to the store, but may be absolute URLs. UNC paths cannot be
+    // reached from here.
+    let target = target.trim().replace('\\', "/");
+    match Url::parse(&target) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(url),
+        Ok(_) => Err(format!("points to unsupported location {target}")),
+        Err(_) if target.starts_with("//") => {
+            Err(format!("points to unreachable location {target}"))
+        }
+        Err(_) => base
+            .join(target.trim_start_matches('/'))
+            .map_err(|e| format!("points to malformed location {target} ({e})")),
+    }
+}
+
+/// Extract the (first) file held by a cabinet.
+fn expand_cab(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut cabinet = cab::Cabinet::new(Cursor::new(data)).context("malformed cabinet")?;
+    let name = cabinet
+        .folder_entries()
+        .flat_map(|f| f.file_entries())
+        .next()
+        .context("cabinet is empty")?
+        .name()
+        .to_string();
+
+    let mut contents = Vec::new();
+    cabinet
+        .read_file(&name)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .with_context(|| format!("failed to extract {name} from cabinet"))?;
+
+    Ok(contents)
+}
+
+/// Fetch `url` from the server at index `idx`, returning `None` if it is unsuccessful.
+async fn get(state: &AppState, idx: usize, url: &Url) -> anyhow::Result<Option<Bytes>> {
+    let server = &state.config.servers[idx];
+    if !host_allowed(state.config.allowed_upstream_hosts.as_deref(), url) {
+        warn!("refusing to send request to {url}, as its host is not in `allowed_upstream_hosts`");
+        return Ok(None);
+    }
+
+    // N.B: Pointers may name arbitrary hosts, which must never receive this server's credentials.
+    let res = if url.origin() == server.url.origin() {
+        let req = upstream_request(
+            &state.server_clients[idx],
+            server,
+            state.token.as_ref(),
+            url,
+            None,
+        )
+        .await?;
+        send_request(&state.config, server, req).await?
+    } else {
+        debug!(
+            "fetching {url} without credentials, as it is not on {}",
+            server.url
+        );
+        state.client.get(url.clone()).send().await?
+    };
+    if !res.status().is_success() {
+        debug!("{url} responded with {}", res.status());
+        return Ok(None);
+    }
+
+    Ok(Some(
+        res.bytes().await.context("failed to read response body")?,
+    ))
+}
+
+/// Download `url` and, if it names a compressed symbol, expand it.
+async fn get_symbol(state: &AppState, idx: usize, url: &Url) -> anyhow::Result<Option<Bytes>> {
+    let Some(body) = get(state, idx, url).await? else {
+        return Ok(None);
+    };
+
+    if !url.path().ends_with('_') {
+        return Ok(Some(body));
+    }
+
+    // N.B: Expanding a large symbol takes a while, so keep it off of the async runtime.
+    let contents = tokio::task::spawn_blocking(move || expand_cab(&body))
+        .await
+        .context("failed to expand cabinet")??;
+
+    Ok(Some(contents.into()))
+}
+
+/// Attempt to fetch `name1/hash/name2` from the server at index `idx` via the fallbacks used by
+/// classic symbol stores, returning the URL it was found at and its (expanded) contents.
+pub async fn fetch(
+    state: &AppState,
+    idx: usize,
+    name1: &str,
+    hash: &str,
+    name2: &str,
+) -> anyhow::Result<Option<(Url, Bytes)>> {
+    let server = &state.config.servers[idx];
+
+    if let Some(compressed) = compressed_name(name2) {
+        let url = symbol_url(server, name1, hash, &compressed)?;
+        if let Some(body) = get_symbol(state, idx, &url).await? {
+            return Ok(Some((url, body)));
+        }
+    }
+
+    let ptr = symbol_url(server, name1, hash, FILE_PTR)?;
+    let Some(body) = get(state, idx, &ptr).await? else {
+        return Ok(None);
+    };
+
+    let url = match parse_pointer(&server.url, &String::from_utf8_lossy(&body)) {
+        Ok(url) => url,
+        Err(reason) => {
+            debug!("{ptr}: {reason}");
+            return Ok(None);
+        }
+    };
+
+    Ok(get_symbol(state, idx, &url).await?.map(|body| (url, body)))
+}
+
+/// Serve a symbol `found` via [`fetch`] (at the given URL), mirroring it to `cache` at `cache_path`.
+///
+/// `cache` is unset if the symbol is excluded from caching, and `hot` is whether it has been
+/// requested often enough to be cached.
+pub async fn respond(
+    state: &AppState,
+    idx: usize,
+    cache: Option<&ConfigCache>,
+    hot: bool,
+    cache_path: &str,
+    name2: &str,
+    (url, body): (Url, Bytes),
+) -> anyhow::Result<Response> {
+    let config = &state.config;
+    let sha256 = config
+        .content_digest
+        .then(|| encode_digest(Sha256::new_with_prefix(&body)));
+
+    // N.B: The symbol has already been received in full, so it is mirrored before being served.
+    mirror_complete(state, cache, cache_path, hot, &url, &body, sha256.clone()).await;
+
+    let mut response = Response::builder()
+        .header(UPSTREAM_SOURCE, "server")
+        .header(UPSTREAM_SERVER, config.servers[idx].url.as_str())
+        .header(X_CACHE, "MISS");
+    if let Some(sha256) = sha256 {
+        response = response.header(REPR_DIGEST, format!("sha-256=:{sha256}:"));
+    }
+
+    Stats::add(&state.stats.upstream_hits, 1);
+    response
+        .header(
+            header::CONTENT_TYPE,
+            content_type(name2, &config.content_types),
+        )
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .context("failed to build response body")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://symbols.example.com/store/").unwrap()
+    }
+
+    #[test]
+    fn compressed_names() {
+        assert_eq!(compressed_name("foo.pdb").as_deref(), Some("foo.pd_"));
+        assert_eq!(compressed_name("foo.pd_"), None);
+        assert_eq!(compressed_name(""), None);
+    }
+
+    #[test]
+    fn pointer_relative() {
+        let url = parse_pointer(&base(), "PATH:foo.pdb\\ABC1\\foo.pdb\r\n").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://symbols.example.com/store/foo.pdb/ABC1/foo.pdb"
+        );
+
+        // A leading separator is still relative to the store, not the host.
+        let url = parse_pointer(&base(), "PATH:\\foo.pdb").unwrap();
+        assert_eq!(url.as_str(), "https://symbols.example.com/store/foo.pdb");
+    }
+
+    #[test]
+    fn pointer_absolute() {
+        let url = parse_pointer(&base(), "PATH: https://other.example.com/foo.pdb").unwrap();
+        assert_eq!(url.as_str(), "https://other.example.com/foo.pdb");
+        assert_ne!(url.origin(), base().origin());
+    }
+
+    #[test]
+    fn pointer_unreachable() {
+        assert!(parse_pointer(&base(), "PATH:\\\\share\\symbols\\foo.pdb").is_err());
+        assert!(parse_pointer(&base(), "PATH:ftp://example.com/foo.pdb").is_err());
+        assert!(parse_pointer(&base(), "PATH:file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn pointer_message() {
+        assert_eq!(
+            parse_pointer(&base(), "MSG: symbol was deleted\n"),
+            Err("MSG: symbol was deleted".to_string())
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! An explicitly ordered chain of Azure credentials, and the wrappers applied to credentials.
+use std::sync::{Arc, Mutex, RwLock};
+
+use azure_core::{
+    auth::{AccessToken, TokenCredential},
+    error::{Error, ErrorKind},
+};
+use azure_identity::{
+    AppServiceManagedIdentityCredential, AzureCliCredential, EnvironmentCredential,
+    TokenCredentialOptions, VirtualMachineManagedIdentityCredential, WorkloadIdentityCredential,
+};
+use tracing::{info, warn};
+
+use crate::ConfigCredential;
+
+/// A credential that attempts each of its sources in order, returning the first token acquired.
+///
+/// Unlike Azure's default credential chain, the order of the sources is chosen by the user.
+#[derive(Debug)]
+pub struct ChainedCredential {
+    sources: Vec<(ConfigCredential, Arc<dyn TokenCredential>)>,
+    /// The source that most recently produced a token, used to log changes in the active source
+    active: Mutex<Option<ConfigCredential>>,
+}
+
+impl ChainedCredential {
+    /// Build a chain from the given credential kinds, in order.
+    ///
+    /// Credentials that cannot be constructed in this environment (e.g. due to missing
+    /// environment variables) are skipped with a warning.
+    pub fn new(kinds: &[ConfigCredential]) -> anyhow::Result<Self> {
+        let mut sources = Vec::new();
+
+        for &kind in kinds {
+            match create_credential(kind) {
+                Ok(cred) => sources.push((kind, cred)),
+                Err(e) => warn!(
+                    "{:?}",
+                    anyhow::Error::new(e).context(format!("skipping {kind:?} credential"))
+                ),
+            }
+        }
+
+        if sources.is_empty() {
+            anyhow::bail!("none of the configured credentials are available");
+        }
+
+        Ok(Self {
+            sources,
+            active: Mutex::new(None),
+        })
+    }
+}
+
+/// Construct a single credential of the given kind.
+fn create_credential(kind: ConfigCredential) -> azure_core::Result<Arc<dyn TokenCredential>> {
+    let options = TokenCredentialOptions::default();
+
+    Ok(match kind {
+        ConfigCredential::ManagedIdentity => {
+            // N.B: App Service exposes its own managed identity endpoint. Prefer it if present,
+            // and otherwise fall back to IMDS.
+            match AppServiceManagedIdentityCredential::create(options.clone()) {
+                Ok(cred) => Arc::new(cred),
+                Err(_) => Arc::new(VirtualMachineManagedIdentityCredential::new(options)),
+            }
+        }
+        ConfigCredential::Environment => Arc::new(EnvironmentCredential::create(options)?),
+        ConfigCredential::AzureCli => Arc::new(AzureCliCredential::new()),
+        ConfigCredential::WorkloadIdentity => {
+            Arc::new(WorkloadIdentityCredential::create(options)?)
+        }
+    })
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ChainedCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        let mut errors = Vec::new();
+
+        for (kind, source) in &self.sources {
+            match source.get_token(scopes).await {
+                Ok(token) => {
+                    let mut active = self.active.lock().unwrap();
+                    if *active != Some(*kind) {
+                        info!("acquired token using {kind:?} credential");
+                        *active = Some(*kind);
+                    }
+
+                    return Ok(token);
+                }
+                Err(e) => errors.push(format!("{kind:?}: {e}")),
+            }
+        }
+
+        Err(Error::with_message(ErrorKind::Credential, || {
+            format!(
+                "every configured credential failed to acquire a token:\n{}",
+                errors.join("\n")
+            )
+        }))
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        for (_, source) in &self.sources {
+            source.clear_cache().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A credential that can be replaced while the proxy is running, e.g. after a secret rotation.
+#[derive(Debug)]
+pub struct ReloadableCredential {
+    inner: RwLock<Arc<dyn TokenCredential>>,
+}
+
+impl ReloadableCredential {
+    pub fn new(inner: Arc<dyn TokenCredential>) -> Self {
+        Self {
+            inner: RwLock::new(inner),
+        }
+    }
+
+    /// Atomically replace the underlying credential. Requests already in flight keep using the
+    /// credential they started with.
+    pub fn replace(&self, inner: Arc<dyn TokenCredential>) {
+        *self.inner.write().unwrap() = inner;
+    }
+
+    fn current(&self) -> Arc<dyn TokenCredential> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ReloadableCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        self.current().get_token(scopes).await
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        self.current().clear_cache().await
+    }
+}
+
+/// A credential that requests tokens for a fixed scope, whatever scope it is asked for.
+///
+/// The Azure SDK hard-codes the public cloud's scopes, which sovereign clouds do not accept.
+#[derive(Debug)]
+pub struct ScopedCredential {
+    inner: Arc<dyn TokenCredential>,
+    scope: String,
+}
+
+impl ScopedCredential {
+    pub fn new(inner: Arc<dyn TokenCredential>, scope: String) -> Self {
+        Self { inner, scope }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ScopedCredential {
+    async fn get_token(&self, _scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        self.inner.get_token(&[&self.scope]).await
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        self.inner.clear_cache().await
+    }
+}
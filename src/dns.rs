@@ -0,0 +1,56 @@
+//! DNS resolution for upstream requests.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tokio::time::Instant;
+
+/// A resolver that caches the addresses of hosts for a fixed amount of time.
+#[derive(Clone)]
+pub struct CachingResolver {
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>>,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cache: Arc::default(),
+        }
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+
+            if let Some((expiry, addrs)) = this.cache.lock().unwrap().get(&host) {
+                if *expiry > Instant::now() {
+                    let addrs: Addrs = Box::new(addrs.clone().into_iter());
+                    return Ok(addrs);
+                }
+            }
+
+            // N.B: The port is ignored by `reqwest`, which substitutes the port of the request URL.
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+
+            this.cache
+                .lock()
+                .unwrap()
+                .insert(host, (Instant::now() + this.ttl, addrs.clone()));
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
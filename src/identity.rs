@@ -0,0 +1,292 @@
+//! Extraction of the identity (the `hash` path segment) embedded in PE images and PDBs.
+//!
+//! Symbol stores index PE images by their timestamp and image size, and PDBs by their GUID and
+//! age. Recomputing that identity from a downloaded file catches stores that return the wrong
+//! file for a request.
+
+/// The magic at the start of an MSF 7.0 (i.e. modern) PDB.
+const PDB_MAGIC: &[u8] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+
+/// The stream holding the PDB's GUID.
+const PDB_INFO_STREAM: usize = 1;
+
+/// The stream holding the debug information, whose age matches the one referenced by images.
+const DBI_STREAM: usize = 3;
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset.checked_add(2)?)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+/// Determine the identity of the symbol file named `name` from its contents.
+///
+/// Returns `None` if the file is of a type whose identity is not understood, or cannot be parsed.
+pub fn identity(name: &str, data: &[u8]) -> Option<String> {
+    let ext = std::path::Path::new(name)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+
+    match ext.as_str() {
+        "exe" | "dll" | "sys" => pe_identity(data),
+        "pdb" => pdb_identity(data),
+        _ => None,
+    }
+}
+
+/// A PE image is identified by `TimeDateStamp` (as 8 hex digits) followed by `SizeOfImage`.
+fn pe_identity(data: &[u8]) -> Option<String> {
+    let pe = u32_at(data, 0x3c)? as usize;
+    if data.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    let timestamp = u32_at(data, pe + 8)?;
+    // N.B: `SizeOfImage` is at the same offset within both PE32 and PE32+ optional headers.
+    let size_of_image = u32_at(data, pe + 24 + 56)?;
+
+    Some(format!("{timestamp:08X}{size_of_image:X}"))
+}
+
+/// A PDB is identified by its GUID followed by its age.
+fn pdb_identity(data: &[u8]) -> Option<String> {
+    if !data.starts_with(PDB_MAGIC) {
+        return None;
+    }
+
+    let block_size = u32_at(data, 32)? as usize;
+    let directory_size = u32_at(data, 44)? as usize;
+    let block_map = u32_at(data, 52)? as usize;
+    if block_size == 0 {
+        return None;
+    }
+
+    let block = |index: usize| {
+        data.get(index.checked_mul(block_size)?..)?
+            .get(..block_size)
+    };
+
+    // The stream directory is scattered across the blocks listed in the block map.
+    let directory_blocks = directory_size.div_ceil(block_size);
+    let map = block(block_map)?;
+    let mut directory = Vec::with_capacity((directory_blocks * block_size).min(data.len()));
+    for i in 0..directory_blocks {
+        directory.extend_from_slice(block(u32_at(map, i * 4)? as usize)?);
+    }
+
+    // The directory lists the size of every stream, followed by the blocks of each stream.
+    let streams = u32_at(&directory, 0)? as usize;
+    let sizes = (0..streams)
+        .map(|i| u32_at(&directory, 4 + i * 4).map(|s| if s == u32::MAX { 0 } else { s as usize }))
+        .collect::<Option<Vec<_>>>()?;
+
+    let blocks = 4 + streams * 4;
+    let stream = |index: usize| -> Option<Vec<u8>> {
+        let start = blocks
+            + sizes
+                .get(..index)?
+                .iter()
+                .map(|s| s.div_ceil(block_size) * 4)
+                .sum::<usize>();
+
+        let size = *sizes.get(index)?;
+        let mut contents = Vec::with_capacity(size.min(data.len()));
+        for i in 0..size.div_ceil(block_size) {
+            contents.extend_from_slice(block(u32_at(&directory, start + i * 4)? as usize)?);
+        }
+
+        contents.truncate(size);
+        Some(contents)
+    };
+
+    // The PDB info stream holds the version, signature, age, and GUID.
+    let info = stream(PDB_INFO_STREAM)?;
+    let guid = info.get(12..28)?;
+
+    // N.B: Images reference the age recorded in the DBI stream, which may lag behind the age in
+    // the info stream. Prefer it if present.
+    let age = stream(DBI_STREAM)
+        .and_then(|dbi| u32_at(&dbi, 8))
+        .or_else(|| u32_at(&info, 8))?;
+
+    Some(format!(
+        "{:08X}{:04X}{:04X}{}{age:X}",
+        u32_at(guid, 0)?,
+        u16_at(guid, 4)?,
+        u16_at(guid, 6)?,
+        guid[8..]
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<String>(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 512;
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Build a minimal PE image with the given timestamp and image size.
+    fn pe(timestamp: u32, size_of_image: u32) -> Vec<u8> {
+        let mut data = vec![0; 0x200];
+        data[..2].copy_from_slice(b"MZ");
+        put_u32(&mut data, 0x3c, 0x80);
+        data[0x80..0x84].copy_from_slice(b"PE\0\0");
+        put_u32(&mut data, 0x80 + 8, timestamp);
+        put_u32(&mut data, 0x80 + 24 + 56, size_of_image);
+        data
+    }
+
+    const GUID: [u8; 16] = [
+        0x78, 0x56, 0x34, 0x12, 0xbc, 0x9a, 0xf0, 0xde, 1, 2, 3, 4, 5, 6, 7, 8,
+    ];
+
+    /// Build a minimal MSF 7.0 PDB with the given GUID, info stream age, and (if any) DBI age.
+    ///
+    /// Block 0 holds the superblock, block 2 the block map, block 3 the stream directory, block 4
+    /// the info stream, and block 5 the DBI stream.
+    fn pdb(guid: [u8; 16], age: u32, dbi_age: Option<u32>) -> Vec<u8> {
+        let mut data = vec![0; 6 * BLOCK_SIZE];
+        data[..PDB_MAGIC.len()].copy_from_slice(PDB_MAGIC);
+        put_u32(&mut data, 32, BLOCK_SIZE as u32);
+        put_u32(&mut data, 36, 1);
+        put_u32(&mut data, 40, 6);
+        put_u32(&mut data, 52, 2);
+
+        let (streams, stream_blocks): (&[u32], &[u32]) = match dbi_age {
+            Some(_) => (&[0, 28, 0, 12], &[4, 5]),
+            None => (&[0, 28], &[4]),
+        };
+
+        let directory = 3 * BLOCK_SIZE;
+        put_u32(&mut data, directory, streams.len() as u32);
+        for (i, size) in streams.iter().enumerate() {
+            put_u32(&mut data, directory + 4 + i * 4, *size);
+        }
+        for (i, block) in stream_blocks.iter().enumerate() {
+            put_u32(&mut data, directory + 4 + streams.len() * 4 + i * 4, *block);
+        }
+        let directory_size = 4 + streams.len() * 4 + stream_blocks.len() * 4;
+        put_u32(&mut data, 44, directory_size as u32);
+        put_u32(&mut data, 2 * BLOCK_SIZE, 3);
+
+        let info = 4 * BLOCK_SIZE;
+        put_u32(&mut data, info, 20000404);
+        put_u32(&mut data, info + 8, age);
+        data[info + 12..info + 28].copy_from_slice(&guid);
+
+        if let Some(dbi_age) = dbi_age {
+            let dbi = 5 * BLOCK_SIZE;
+            put_u32(&mut data, dbi, u32::MAX);
+            put_u32(&mut data, dbi + 4, 19990903);
+            put_u32(&mut data, dbi + 8, dbi_age);
+        }
+
+        data
+    }
+
+    #[test]
+    fn pe_identity_from_header() {
+        let data = pe(0x5e8c1234, 0x1a000);
+        assert_eq!(
+            identity("ntdll.dll", &data).as_deref(),
+            Some("5E8C12341A000")
+        );
+        assert_eq!(
+            identity("NTOSKRNL.EXE", &data).as_deref(),
+            Some("5E8C12341A000")
+        );
+    }
+
+    #[test]
+    fn pe_identity_pads_timestamp() {
+        let data = pe(0x1234, 0x2000);
+        assert_eq!(identity("foo.sys", &data).as_deref(), Some("000012342000"));
+    }
+
+    #[test]
+    fn pe_identity_rejects_bad_signature() {
+        let mut data = pe(0x5e8c1234, 0x1a000);
+        data[0x80] = b'X';
+        assert_eq!(identity("foo.dll", &data), None);
+    }
+
+    #[test]
+    fn pe_identity_rejects_truncated_input() {
+        let data = pe(0x5e8c1234, 0x1a000);
+        assert_eq!(identity("foo.dll", &data[..0x80 + 24 + 56 + 2]), None);
+        assert_eq!(identity("foo.dll", &data[..0x82]), None);
+        assert_eq!(identity("foo.dll", &data[..0x3e]), None);
+        assert_eq!(identity("foo.dll", &[]), None);
+    }
+
+    #[test]
+    fn pe_identity_rejects_out_of_range_header_offset() {
+        let mut data = pe(0x5e8c1234, 0x1a000);
+        put_u32(&mut data, 0x3c, u32::MAX);
+        assert_eq!(identity("foo.dll", &data), None);
+    }
+
+    #[test]
+    fn pdb_identity_prefers_dbi_age() {
+        let data = pdb(GUID, 2, Some(3));
+        assert_eq!(
+            identity("foo.pdb", &data).as_deref(),
+            Some("123456789ABCDEF001020304050607083")
+        );
+    }
+
+    #[test]
+    fn pdb_identity_falls_back_to_info_age() {
+        let data = pdb(GUID, 0x1f, None);
+        assert_eq!(
+            identity("foo.pdb", &data).as_deref(),
+            Some("123456789ABCDEF001020304050607081F")
+        );
+    }
+
+    #[test]
+    fn pdb_identity_rejects_bad_magic() {
+        let mut data = pdb(GUID, 2, Some(3));
+        data[0] = b'm';
+        assert_eq!(identity("foo.pdb", &data), None);
+    }
+
+    #[test]
+    fn pdb_identity_rejects_truncated_input() {
+        let data = pdb(GUID, 2, Some(3));
+
+        // Without the info stream, there is no GUID.
+        assert_eq!(identity("foo.pdb", &data[..4 * BLOCK_SIZE + 20]), None);
+        // Without the stream directory, there are no streams.
+        assert_eq!(identity("foo.pdb", &data[..3 * BLOCK_SIZE]), None);
+        // Without the superblock, there is nothing.
+        assert_eq!(identity("foo.pdb", &data[..40]), None);
+        assert_eq!(identity("foo.pdb", &data[..PDB_MAGIC.len() - 1]), None);
+    }
+
+    #[test]
+    fn pdb_identity_rejects_zero_block_size() {
+        let mut data = pdb(GUID, 2, Some(3));
+        put_u32(&mut data, 32, 0);
+        assert_eq!(identity("foo.pdb", &data), None);
+    }
+
+    #[test]
+    fn unknown_extensions_have_no_identity() {
+        assert_eq!(identity("foo.txt", &pe(0x5e8c1234, 0x1a000)), None);
+        assert_eq!(identity("foo", &pdb(GUID, 2, Some(3))), None);
+    }
+}
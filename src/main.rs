@@ -1,38 +1,78 @@
 use anyhow::Context;
 use axum::{
-    body::Body,
-    extract::{FromRef, Path, State},
+    body::{Body, Bytes},
+    extract::{FromRef, Path, Request, State},
     http::{HeaderMap, HeaderValue},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{get, post},
+    BoxError, Extension, Router,
 };
-use azure_core::{auth::TokenCredential, prelude::Metadata};
-use azure_storage::StorageCredentials;
-use azure_storage_blobs::blob::{BlobBlockType, BlockList};
+use azure_core::auth::TokenCredential;
 use base64::Engine;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::{InfoLevel, LevelFilter, Verbosity};
 use figment::{providers::Format, Figment};
 use futures::{Stream, StreamExt, TryStreamExt};
+use http_body_util::StreamBody;
+use hyper::body::Frame;
+use lru::LruCache;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 use reqwest::{header, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use sha2::{Digest, Sha256};
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    collections::HashMap,
+    io::Read,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
     path::PathBuf,
     pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use thiserror::Error;
-use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpListener,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_util::io::ReaderStream;
-use tower_http::trace::TraceLayer;
-use tracing::{error, info, trace};
+use tokio_util::sync::CancellationToken;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{debug, error, info, trace, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use url::Url;
-use uuid::Uuid;
+
+use status::Stats;
+use throttle::RateLimiter;
+
+mod admin;
+mod cache;
+mod coalesce;
+mod compressed;
+mod credential;
+mod dns;
+#[cfg(feature = "verify-identity")]
+mod identity;
+#[cfg(feature = "otel")]
+mod otel;
+mod prewarm;
+mod resume;
+mod source;
+mod spill;
+mod status;
+mod throttle;
+#[cfg(unix)]
+mod unix;
 
 /// The header used to indicate the upstream source where a symbol came from.
 const UPSTREAM_SOURCE: &str = "X-Upstream-Source";
@@ -40,9 +80,57 @@ const UPSTREAM_SOURCE: &str = "X-Upstream-Source";
 /// The header used to indicate the upstream server that a symbol was fetched from.
 const UPSTREAM_SERVER: &str = "X-Upstream-Server";
 
+/// The header used to report the host (or "cache") that served a symbol, if enabled.
+const SYMBOL_SOURCE: &str = "X-Symbol-Source";
+
+/// The header used to report whether a symbol was served from the cache (`HIT`) or not (`MISS`).
+const X_CACHE: &str = "X-Cache";
+
+/// The default layout of symbol paths on upstream servers.
+const DEFAULT_PATH_TEMPLATE: &str = "{name1}/{hash}/{name2}";
+
+/// The default number of times a request to an upstream server is retried.
+const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// The default `User-Agent` sent to upstream servers.
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The default amount of time (in seconds) idle upstream connections are kept open.
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90;
+
+/// The default maximum number of symbols waiting in the prewarm queue.
+const DEFAULT_PREWARM_QUEUE_SIZE: usize = 10_000;
+
+/// The default route prefix source files are served under.
+const DEFAULT_SOURCE_PREFIX: &str = "/sources";
+
+/// The default number of times an interrupted download is resumed.
+const DEFAULT_MAX_RESUME_ATTEMPTS: u32 = 2;
+
+/// The default maximum size (in bytes) of the symbols whose identity is verified.
+const DEFAULT_VERIFY_IDENTITY_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The default maximum number of symbols that may be mirrored to the cache concurrently.
+const DEFAULT_MAX_CONCURRENT_MIRRORS: usize = 64;
+
+/// The maximum number of recent misses remembered across all servers.
+const NEGATIVE_CACHE_CAPACITY: usize = 10_000;
+
+/// How long to wait before retrying to mirror a symbol that the cache was unavailable for.
+const CACHE_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// The default amount of time (in seconds) a request waits to fetch a symbol from upstream.
+const DEFAULT_FETCH_QUEUE_TIMEOUT: u64 = 10;
+
+/// How long symbol downloads are given to abort once the shutdown grace period elapses.
+const MIRROR_ABORT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The internal authentication token provided to us from Azure.
 const INTERNAL_AUTH_TOKEN: &str = "x-ms-auth-internal-token";
 
+/// The header (or trailer) holding the digest of a symbol, as defined by RFC 9530.
+const REPR_DIGEST: &str = "Repr-Digest";
+
 /// `axum`-compatible error handler.
 #[derive(Debug, Error)]
 #[error(transparent)]
@@ -59,26 +147,98 @@ impl IntoResponse for Error {
     }
 }
 
+/// A secret configuration value, which is redacted when the configuration is logged.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+struct Secret(String);
+
+impl Secret {
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 struct ConfigAuth {
     /// The scope of the authentication token
     scope: String,
 }
 
+/// Credentials for servers that use HTTP basic authentication.
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigBasicAuth {
+    username: String,
+    password: ConfigHeaderValue,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 struct ConfigAzureCache {
     /// The Azure storage account to use
     storage_account: String,
     /// The container within the storage account to use
     storage_container: String,
+    /// The blob service endpoint of the storage account, if it is not in the public Azure cloud
+    endpoint: Option<Url>,
+    /// The scope tokens for the storage account are requested for, if it is not in the public
+    /// Azure cloud
+    scope: Option<String>,
     /// Access key
-    key: Option<String>,
+    key: Option<Secret>,
+    /// The size (in bytes) of each ranged request used to stream a blob out of the cache
+    chunk_size: Option<u64>,
+    /// The size (in bytes) of each block uploaded when mirroring a symbol to the cache
+    block_size: Option<usize>,
+    /// A local directory mirrored symbols are also written to, and served from in preference to
+    /// the storage account
+    local_cache_dir: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct ConfigFsCache {
     /// The path to the cache directory
     path: PathBuf,
+    /// Store newly cached symbols compressed with zstd
+    #[serde(default)]
+    compress_cache: bool,
+    /// The size (in bytes) of the buffer used to batch writes to cache files
+    write_buffer_size: Option<usize>,
+    /// Keep an in-memory index of cached symbols, so that misses do not touch the filesystem
+    #[serde(default)]
+    index: bool,
+    /// The amount of free space (in bytes) that must remain on the cache's volume after mirroring
+    /// a symbol, below which symbols are served without being mirrored
+    min_free_bytes: Option<u64>,
+}
+
+/// A cache held entirely in memory, which is lost when the proxy exits.
+#[cfg(feature = "memory-cache")]
+#[derive(Deserialize, Debug, Clone, Default)]
+struct ConfigMemoryCache {
+    /// The maximum number of symbols held, evicting the least recently used beyond it
+    max_entries: Option<usize>,
+    /// The cached symbols, shared between every copy of this configuration
+    #[serde(skip)]
+    entries: Arc<std::sync::OnceLock<Mutex<LruCache<String, Bytes>>>>,
+}
+
+#[cfg(feature = "memory-cache")]
+impl ConfigMemoryCache {
+    fn entries(&self) -> &Mutex<LruCache<String, Bytes>> {
+        self.entries.get_or_init(|| {
+            let max = self
+                .max_entries
+                .and_then(NonZeroUsize::new)
+                .unwrap_or(NonZeroUsize::new(1_000).unwrap());
+
+            Mutex::new(LruCache::new(max))
+        })
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -86,6 +246,8 @@ struct ConfigFsCache {
 enum ConfigCache {
     Azure(ConfigAzureCache),
     Fs(ConfigFsCache),
+    #[cfg(feature = "memory-cache")]
+    Memory(ConfigMemoryCache),
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -94,14 +256,448 @@ struct ConfigServer {
     url: Url,
     /// Authentication settings
     auth: Option<ConfigAuth>,
+    /// The layout of symbol paths on this server, relative to `url`
+    path_template: Option<String>,
+    /// The maximum rate (in bytes per second) at which symbols are downloaded from this server
+    bandwidth_limit: Option<u64>,
+    /// Overrides the `Host` header sent to this server
+    host_header: Option<String>,
+    /// Connect to this server over a Unix domain socket, rather than the host in `url`
+    unix_socket: Option<PathBuf>,
+    /// How unsuccessful status codes from this server are handled, keyed by status code (e.g.
+    /// `403`) or class (e.g. `5xx`)
+    #[serde(default)]
+    status_actions: HashMap<String, StatusAction>,
+    /// The number of times a request to this server is retried if its status is retryable
+    max_retries: Option<u32>,
+    /// The HTTP method used to request symbols from this server (defaults to `GET`)
+    method: Option<String>,
+    /// A JSON request body sent with each symbol request, expanded like `path_template`
+    body_template: Option<String>,
+    /// The amount of time (in seconds) a symbol missing from this server is remembered as
+    /// missing, during which the server is not asked for it again
+    negative_ttl: Option<u64>,
+    /// Fall back to the compressed (`foo.pd_`) and `file.ptr` forms of symbols missing from this
+    /// server, as stored by `symstore`
+    #[serde(default)]
+    compressed_fallback: bool,
+    /// The pool of equivalent servers this server belongs to. Servers in a pool are consulted in
+    /// weighted round-robin order, at the position of the pool's first server.
+    pool: Option<String>,
+    /// The relative share of requests this server receives first within its pool (defaults to 1)
+    weight: Option<u64>,
+    /// The number of times an interrupted download from this server is resumed with a ranged
+    /// request, if the server supports ranges
+    max_resume_attempts: Option<u32>,
+    /// Additional headers sent with each request to this server
+    #[serde(default)]
+    headers: HashMap<String, ConfigHeaderValue>,
+    /// Authenticate to this server with HTTP basic authentication, unless `auth` is also set
+    basic_auth: Option<ConfigBasicAuth>,
+    /// When racing servers, probe this server before racing the rest
+    #[serde(default)]
+    preferred: bool,
+    /// Overrides `connect_timeout` for this server
+    connect_timeout: Option<u64>,
+    /// Overrides `read_timeout` for this server
+    read_timeout: Option<u64>,
+    /// Overrides `upstream_timeout` for this server
+    timeout: Option<u64>,
+}
+
+/// The value of a custom request header, which may be a secret (e.g. an API key).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum ConfigHeaderValue {
+    /// A literal value
+    Value(Secret),
+    /// A value read from an environment variable
+    Env { env: String },
+}
+
+impl ConfigHeaderValue {
+    fn resolve(&self) -> anyhow::Result<String> {
+        match self {
+            Self::Value(value) => Ok(value.expose().to_string()),
+            Self::Env { env } => std::env::var(env)
+                .with_context(|| format!("failed to read environment variable `{env}`")),
+        }
+    }
+}
+
+/// How an unsuccessful status code from an upstream server is handled.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum StatusAction {
+    /// Treat the symbol as missing from this server, and continue on to the next one
+    Miss,
+    /// Retry the request to this server, treating it as unreachable if retries are exhausted
+    Retry,
+    /// Stop resolving the symbol, and fail the request
+    Fail,
+}
+
+impl ConfigServer {
+    /// The HTTP method used to request symbols from this server.
+    fn method(&self) -> reqwest::Method {
+        self.method
+            .as_deref()
+            .and_then(|m| reqwest::Method::from_str(&m.to_uppercase()).ok())
+            .unwrap_or(reqwest::Method::GET)
+    }
+
+    /// Determine how an unsuccessful `status` from this server should be handled.
+    fn status_action(&self, status: StatusCode) -> StatusAction {
+        let code = status.as_u16();
+
+        if let Some(action) = self
+            .status_actions
+            .get(&code.to_string())
+            .or_else(|| self.status_actions.get(&format!("{}xx", code / 100)))
+        {
+            return *action;
+        }
+
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => StatusAction::Retry,
+            s if s.is_server_error() => StatusAction::Retry,
+            _ => StatusAction::Miss,
+        }
+    }
+}
+
+/// A source of Azure credentials.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ConfigCredential {
+    ManagedIdentity,
+    Environment,
+    AzureCli,
+    WorkloadIdentity,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigDns {
+    /// Static addresses for hosts, bypassing DNS resolution entirely
+    #[serde(default)]
+    overrides: HashMap<String, Vec<IpAddr>>,
+    /// The amount of time (in seconds) resolved addresses are cached for
+    cache_ttl: Option<u64>,
+}
+
+/// How the case of symbol names is treated when forming cache keys.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum ConfigKeyCase {
+    /// Use symbol names exactly as requested
+    #[default]
+    Preserve,
+    /// Lowercase symbol names, so that names differing only in case share a cache entry
+    Lower,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+struct ConfigOtel {
+    /// The OTLP (gRPC) endpoint to export traces to
+    endpoint: String,
+    /// The fraction of traces to export, between 0 and 1
+    sample_rate: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigCacheThreshold {
+    /// The number of times a symbol must be requested before it is cached
+    requests: u32,
+    /// The maximum number of symbols whose request counts are tracked
+    tracked: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigNotFound {
+    /// The status code returned for missing symbols, which must be in the 4xx range
+    status: Option<u16>,
+    /// The body returned for missing symbols
+    body: Option<String>,
+}
+
+/// How a symbol is served when it cannot be mirrored because the cache is unavailable.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ConfigCacheUnavailable {
+    /// Return an error to the client, so that the problem is visible
+    Fail,
+    /// Serve the symbol without caching it
+    #[default]
+    StreamOnly,
+    /// Serve the symbol without caching it, and queue it to be mirrored again later
+    RetryLater,
+}
+
+/// Which value is kept when an upstream server sends a header that may only appear once several
+/// times.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum ConfigDuplicateHeaders {
+    /// Keep the first value
+    #[default]
+    First,
+    /// Keep the last value
+    Last,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigResponseHeaders {
+    /// If set, only upstream response headers matching one of these names are forwarded
+    allow: Option<Vec<String>>,
+    /// Upstream response headers matching one of these names are never forwarded
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl ConfigResponseHeaders {
+    /// Determine whether the upstream response header `name` should be forwarded to clients.
+    ///
+    /// Names are matched case-insensitively, and may end with `*` to match a prefix (e.g. `x-ms-*`).
+    fn forwarded(&self, name: &str) -> bool {
+        let matches = |pattern: &String| {
+            let pattern = pattern.to_lowercase();
+            match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            }
+        };
+
+        self.allow.as_ref().map_or(true, |a| a.iter().any(matches))
+            && !self.deny.iter().any(matches)
+    }
+}
+
+/// A logically separate symbol store, served under its own route prefix.
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigRealm {
+    /// The route prefix this realm is served under (e.g. `/teamA`)
+    prefix: String,
+    cache: Option<ConfigCache>,
+    servers: Vec<ConfigServer>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigSources {
+    /// The route prefix source files are served under (defaults to `/sources`)
+    prefix: Option<String>,
+    /// The servers source files are fetched from, relative to their URL
+    servers: Vec<ConfigServer>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ConfigSignatures {
+    /// Additional or overridden signatures, keyed by file extension. An empty signature
+    /// disables verification for that extension.
+    #[serde(default)]
+    extensions: HashMap<String, String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct AppConfig {
     listen_address: Option<SocketAddr>,
+    /// A separate address to serve health and administrative endpoints on, leaving only symbol
+    /// traffic on `listen_address`
+    admin_listen_address: Option<SocketAddr>,
     i_am_not_an_idiot: bool,
+    /// The path prefix every route is served under (e.g. `/symbols`), for deployments behind a
+    /// reverse proxy that does not strip it
+    base_path: Option<String>,
+    /// The maximum amount of time (in seconds) a single request may take, including
+    /// streaming the response body back to the client
+    request_timeout: Option<u64>,
+    /// The maximum amount of time (in seconds) to wait for a connection to an upstream server
+    connect_timeout: Option<u64>,
+    /// The maximum amount of time (in seconds) to wait for data from an upstream server
+    read_timeout: Option<u64>,
+    /// The maximum amount of time (in seconds) a single request to an upstream server may take,
+    /// including downloading the symbol
+    upstream_timeout: Option<u64>,
+    /// How long (in seconds) idle connections to upstream servers are kept open (defaults to 90)
+    pool_idle_timeout: Option<u64>,
+    /// The `User-Agent` sent to upstream servers
+    user_agent: Option<String>,
+    /// The amount of time (in seconds) in-flight requests and mirrors may take to complete once
+    /// shutdown begins before they are cancelled. If unset, they are waited on indefinitely.
+    shutdown_grace_period: Option<u64>,
+    /// Retry acquiring tokens for authenticated servers until they succeed before binding the
+    /// listener, rather than failing startup
+    #[serde(default)]
+    warm_before_serve: bool,
+    /// The credentials to try, in order, when authenticating. If unset, Azure's default
+    /// credential chain is used.
+    credentials: Option<Vec<ConfigCredential>>,
     cache: Option<ConfigCache>,
+    /// Glob patterns matched against the request path (`name1/hash/name2`). Matching requests
+    /// bypass the cache entirely.
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    cache_exclude: Vec<glob::Pattern>,
+    /// A local directory (in the same layout as a filesystem cache) consulted for symbols. It is
+    /// never written to.
+    local_store: Option<PathBuf>,
+    /// Consult the local store before the cache and any upstream server, rather than after
+    #[serde(default)]
+    local_first: bool,
+    /// The maximum rate (in bytes per second) at which symbols are served, across all clients
+    bandwidth_limit: Option<u64>,
+    /// Continue mirroring a symbol to the cache if the client disconnects mid-download, rather
+    /// than cancelling the download
+    #[serde(default = "default_true")]
+    finish_cache_on_client_disconnect: bool,
+    /// Record the SHA-256 of mirrored symbols, and send it in a `Repr-Digest` header (for cache
+    /// hits) or trailer (for clients that accept trailers)
+    #[serde(default)]
+    content_digest: bool,
+    /// Spill symbols being mirrored to disk when the client falls behind, rather than holding
+    /// up the download
+    #[serde(default)]
+    spill_to_disk: bool,
+    /// The directory temporary files (such as spilled symbols) are written to, defaulting to the
+    /// system's temporary directory. This is a top-level setting rather than part of `cache`, as
+    /// spilled symbols are served to clients whichever (if any) cache backend is configured.
+    temp_dir: Option<PathBuf>,
+    /// How the case of symbol names is treated when forming cache keys
+    #[serde(default)]
+    cache_key_case: ConfigKeyCase,
+    /// Which value is kept when upstream repeats a header that may only appear once
+    #[serde(default)]
+    duplicate_headers: ConfigDuplicateHeaders,
+    /// Start with writes to the cache disabled, serving cache hits without mirroring misses
+    #[serde(default)]
+    disable_cache_writes: bool,
+    /// Never serve symbols from the cache, while still mirroring misses to it
+    #[serde(default)]
+    disable_cache_reads: bool,
+    /// Probe every server for a symbol concurrently, rather than consulting them one at a time
+    #[serde(default)]
+    race_servers: bool,
+    /// How symbols are served when the cache is unavailable to mirror them
+    #[serde(default)]
+    cache_unavailable: ConfigCacheUnavailable,
+    /// Serve empty symbols returned by upstream servers, rather than treating them as missing
+    #[serde(default)]
+    allow_empty_symbols: bool,
+    /// The amount of time (in seconds) a symbol missing from a server is remembered as missing,
+    /// for servers that do not set their own `negative_ttl`
+    negative_ttl: Option<u64>,
+    /// Serve Prometheus metrics at `/metrics`
+    #[serde(default)]
+    metrics: bool,
+    /// Have `/healthz` report the proxy as unready if a token cannot be acquired for the first
+    /// authenticated server
+    #[serde(default)]
+    healthz_check_auth: bool,
+    /// The maximum number of queued symbols mirrored at once by the prewarm queue (defaults to 1)
+    prewarm_concurrency: Option<usize>,
+    /// The maximum number of symbols waiting in the prewarm queue (defaults to 10000)
+    prewarm_queue_size: Option<usize>,
+    /// Verify that PE images and PDBs from upstream match the requested hash, treating mismatched
+    /// files as missing. Requires the `verify-identity` feature.
+    #[serde(default)]
+    verify_identity: bool,
+    /// The maximum size (in bytes) of the symbols whose identity is verified, which must be
+    /// buffered in full to do so. Larger symbols (or those of unknown size) are not verified.
+    verify_identity_max_size: Option<u64>,
+    /// If set, symbols are only cached once they have been requested a number of times
+    cache_threshold: Option<ConfigCacheThreshold>,
+    /// Symbols smaller than this size (in bytes) are not cached
+    min_cache_file_size: Option<u64>,
+    /// Symbols larger than this size (in bytes) are not cached
+    max_cache_file_size: Option<u64>,
+    /// Whether symbols whose size is not known up front are cached
+    #[serde(default = "default_true")]
+    cache_unknown_size: bool,
+    /// The maximum number of symbols that may be mirrored to the cache concurrently (defaults to 64)
+    max_concurrent_mirrors: Option<usize>,
+    /// The maximum number of requests that may fetch symbols from upstream servers concurrently
+    max_concurrent_fetches: Option<usize>,
+    /// The amount of time (in seconds) a request waits for one of `max_concurrent_fetches` before
+    /// being rejected
+    fetch_queue_timeout: Option<u64>,
+    /// If set, symbols are only mirrored to the cache if they begin with the signature
+    /// expected for their file type
+    verify_signatures: Option<ConfigSignatures>,
+    /// Download the entire upstream response before sending it to the client with a fixed
+    /// `Content-Length`, rather than streaming it
+    #[serde(default)]
+    buffer_full_response: bool,
+    /// Satisfy ranged requests for uncached symbols by downloading the full symbol from upstream
+    /// and serving the requested range from it
+    #[serde(default)]
+    buffer_ranged_requests: bool,
+    /// The file extensions that may be served. If empty, all files may be served.
+    #[serde(default)]
+    allowed_extensions: Vec<String>,
+    /// Report the host (or "cache") that served each symbol in an `X-Symbol-Source` header
+    #[serde(default)]
+    symbol_source_header: bool,
+    /// Content types served for symbols, keyed by file extension. These extend or override the
+    /// built-in defaults.
+    #[serde(default)]
+    content_types: HashMap<String, String>,
+    /// Replace the content type reported by upstream servers with the one configured for the
+    /// symbol's extension, if any
+    #[serde(default)]
+    override_upstream_content_type: bool,
+    /// If set, requests (and redirects) are only ever sent to upstream servers with one of these hosts
+    allowed_upstream_hosts: Option<Vec<String>>,
+    /// DNS resolution settings for upstream servers
+    dns: Option<ConfigDns>,
+    /// OpenTelemetry trace export settings. Requires the `otel` feature.
+    otel: Option<ConfigOtel>,
+    /// Overrides for the response returned when a symbol cannot be found
+    not_found: Option<ConfigNotFound>,
+    /// Filters the upstream response headers forwarded to clients
+    response_headers: Option<ConfigResponseHeaders>,
+    /// The bearer token required to access administrative endpoints. If unset, those endpoints
+    /// are disabled.
+    admin_token: Option<Secret>,
+    /// The bearer tokens accepted from clients requesting symbols. If empty, symbols may be
+    /// requested without authenticating.
+    #[serde(default)]
+    api_keys: Vec<Secret>,
+    #[serde(default)]
     servers: Vec<ConfigServer>,
+    /// Additional symbol stores, keyed by name, each with their own servers and cache
+    #[serde(default)]
+    realms: HashMap<String, ConfigRealm>,
+    /// If set, source files referenced by source-indexed PDBs are proxied as well
+    sources: Option<ConfigSources>,
+}
+
+impl AppConfig {
+    /// The directory temporary files are written to.
+    fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Iterate over every configured upstream server, including those of realms and sources.
+    fn all_servers(&self) -> impl Iterator<Item = &ConfigServer> {
+        self.servers
+            .iter()
+            .chain(self.realms.values().flat_map(|r| &r.servers))
+            .chain(self.sources.iter().flat_map(|s| &s.servers))
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Deserialize a list of strings into a list of glob patterns.
+fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Vec<glob::Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|p| glob::Pattern::new(p).map_err(serde::de::Error::custom))
+        .collect()
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -109,357 +705,2788 @@ struct Args {
     #[command(flatten)]
     verbosity: Verbosity<InfoLevel>,
 
-    /// Path to the configuration file
+    /// Path to the configuration file, or `-` to read it from stdin
     #[arg(short, long, default_value = "default.toml")]
     config: PathBuf,
+
+    /// Inline TOML configuration, used in place of a configuration file
+    #[arg(long, conflicts_with = "config")]
+    config_inline: Option<String>,
+
+    /// Log filter directives (e.g. `info,hyper=warn`), overriding `RUST_LOG`
+    #[arg(long)]
+    log_filter: Option<String>,
+
+    /// The format logs are written in
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+
+    /// Start with writes to the cache disabled (see `disable_cache_writes`)
+    #[arg(long)]
+    disable_cache_writes: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// The format logs are written to the console in.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// Newline-delimited JSON, for ingestion into log aggregators
+    Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Look up a single symbol using the configured cache and upstream servers, without
+    /// running the server
+    Fetch {
+        name1: String,
+        hash: String,
+        name2: String,
+
+        /// The file to write the symbol to. Defaults to `name2` in the current directory.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Export a manifest of every entry in the configured cache
+    CacheExport {
+        /// The file to write the manifest to. Defaults to stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Validate a manifest written by `cache-export` against the configured cache, e.g. after
+    /// copying the cache's storage to a new instance
+    CacheImport {
+        /// The manifest to validate
+        manifest: PathBuf,
+    },
+    /// Check the configuration and acquire a token for each authenticated server, without
+    /// running the server
+    Validate,
 }
 
+/// Permits limiting the number of requests concurrently fetching symbols from upstream.
+#[derive(Clone)]
+struct FetchPermits(Arc<Semaphore>);
+
 #[derive(Clone, FromRef)]
 struct AppState {
     config: AppConfig,
     token: Arc<dyn TokenCredential>,
+    /// The credential behind `token`, which may be swapped out by an administrator
+    credentials: Arc<credential::ReloadableCredential>,
+    /// Permits limiting the number of symbols concurrently mirrored to the cache
+    mirror_permits: Option<Arc<Semaphore>>,
+    /// Permits limiting the number of requests concurrently fetching symbols from upstream
+    fetch_permits: Option<FetchPermits>,
+    /// The client shared by every request to upstream servers, so that connections are reused
+    client: reqwest::Client,
+    /// The client used for each server, in configuration order. This is `client`, unless the
+    /// server overrides its timeouts.
+    server_clients: Arc<Vec<reqwest::Client>>,
+    /// Caches the addresses of upstream servers, if configured
+    resolver: Option<Arc<dns::CachingResolver>>,
+    /// The number of times recently requested symbols have been fetched from upstream
+    request_counts: Option<Arc<Mutex<LruCache<String, u32>>>>,
+    /// When recent misses expire, keyed by server index (in configuration order) and symbol
+    negative_cache: Option<Arc<Mutex<LruCache<(usize, String), Instant>>>>,
+    /// Limits the rate at which symbols are served to clients
+    global_limiter: Option<Arc<RateLimiter>>,
+    /// Limits the rate at which symbols are downloaded from each server, in configuration order
+    server_limiters: Arc<Vec<Option<Arc<RateLimiter>>>>,
+    stats: Arc<Stats>,
+    /// Cancelled once the shutdown grace period elapses, aborting in-flight requests and mirrors
+    shutdown: CancellationToken,
+    /// In-memory indexes of the filesystem caches that have one, keyed by cache directory
+    cache_indexes: Arc<HashMap<PathBuf, Arc<cache::FsIndex>>>,
+    /// Whether symbols may currently be written to the cache, toggled by administrators
+    cache_writes: Arc<AtomicBool>,
+    /// The number of requests that have been distributed across each server pool
+    pool_counters: Arc<HashMap<String, AtomicU64>>,
+    /// Symbols (`name1/hash/name2`) queued to be mirrored in the background
+    prewarm: tokio::sync::mpsc::Sender<String>,
+    /// The tasks streaming symbols from upstream servers, waited on during shutdown
+    mirrors: Arc<status::MirrorTasks>,
+    /// The symbols currently being downloaded, onto which concurrent requests are coalesced
+    flights: Arc<coalesce::Flights>,
 }
 
-/// Primary endpoint used to proxy a symbol file from the configured upstream server.
-async fn symbol(
-    State(token): State<Arc<dyn TokenCredential>>,
-    State(config): State<AppConfig>,
-    Path((name1, hash, name2)): Path<(String, String, String)>,
-) -> Result<Response, Error> {
-    // Attempt the storage account first, if one is set.
-    if let Some(cache) = &config.cache {
-        match &cache {
-            ConfigCache::Azure(cache) => {
-                let cred = if let Some(key) = &cache.key {
-                    StorageCredentials::access_key(&cache.storage_account, key.clone())
-                } else {
-                    StorageCredentials::token_credential(token.clone())
-                };
+/// Expands a path template, substituting `{name1}`, `{hash}`, and `{name2}` with the
+/// components of the requested symbol.
+fn expand_path_template(template: &str, name1: &str, hash: &str, name2: &str) -> String {
+    template
+        .replace("{name1}", name1)
+        .replace("{hash}", hash)
+        .replace("{name2}", name2)
+}
 
-                let client =
-                    azure_storage_blobs::prelude::ClientBuilder::new(&cache.storage_account, cred)
-                        .blob_client(&cache.storage_container, format!("{name1}/{hash}/{name2}"));
+/// Expands a request body template like [`expand_path_template`], escaping each component such
+/// that it may be safely substituted into a JSON string.
+fn expand_body_template(template: &str, name1: &str, hash: &str, name2: &str) -> String {
+    let escape = |s: &str| {
+        let quoted = serde_json::to_string(s).unwrap_or_default();
+        quoted.trim_matches('"').to_string()
+    };
 
-                if let Ok(props) = client.get_properties().await {
-                    // N.B: Get the blob's data and stream it out directly instead of generating a SAS URL and returning a 302.
-                    //
-                    // This is important because this application may be placed behind a reverse proxy that supports auth,
-                    // and returning an SAS URL subverts the authority of the reverse proxy (e.g. reverse proxy may want
-                    // to log requests or set a time limit, but an SAS URL will allow users to bypass that).
-                    let body = client.get().into_stream().map_ok(|r| r.data).try_flatten();
+    expand_path_template(template, &escape(name1), &escape(hash), &escape(name2))
+}
 
-                    return Ok(Response::builder()
-                        .header(UPSTREAM_SOURCE, "cache")
-                        .header(header::CONTENT_TYPE, "application/octet-stream")
-                        .header(
-                            header::CONTENT_LENGTH,
-                            &props.blob.properties.content_length.to_string(),
-                        )
-                        .status(StatusCode::OK)
-                        .body(Body::from_stream(body))
-                        .context("failed to build response body")?);
-                }
-            }
-            ConfigCache::Fs(cache) => {
-                let path = cache.path.join(format!("{name1}/{hash}/{name2}"));
-                if let Ok(f) = tokio::fs::File::open(path.clone()).await {
-                    let meta = f.metadata().await.context("failed to get file metadata")?;
-                    let body = ReaderStream::new(f);
+/// Determine the signature that a symbol named `name` is expected to begin with, if any.
+fn expected_signature(name: &str, overrides: &HashMap<String, String>) -> Option<Vec<u8>> {
+    let ext = std::path::Path::new(name)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
 
-                    return Ok(Response::builder()
-                        .header(UPSTREAM_SOURCE, "cache")
-                        .header(header::CONTENT_TYPE, "application/octet-stream")
-                        .header(header::CONTENT_LENGTH, meta.len().to_string())
-                        .status(StatusCode::OK)
-                        .body(Body::from_stream(body))
-                        .context("failed to build response body")?);
-                }
-            }
-        }
+    if let Some(signature) = overrides.get(&ext) {
+        return (!signature.is_empty()).then(|| signature.as_bytes().to_vec());
     }
 
-    for server in &config.servers {
-        let url = server
-            .url
-            .join(&format!("{name1}/{hash}/{name2}"))
-            .context("failed to build request url")?;
+    let signature: &[u8] = match ext.as_str() {
+        // Both MSF 7.00 and the legacy 2.00 format begin with this.
+        "pdb" => b"Microsoft C/C++ ",
+        "exe" | "dll" | "sys" | "ocx" | "drv" | "cpl" | "scr" | "efi" => b"MZ",
+        // Compressed symbols (e.g. `foo.pd_`) are stored as cabinet files.
+        _ if ext.ends_with('_') => b"MSCF",
+        _ => return None,
+    };
 
-        // Dispatch a reqwest request to upstream, and serve the response.
-        // https://github.com/tokio-rs/axum/blob/680cdcba7cfa0b4fb37aba0c129ab6e4379bae3b/examples/reqwest-response/src/main.rs#L53-L68
-        let req_builder = reqwest::Client::new().get(url.clone());
+    Some(signature.to_vec())
+}
 
-        // If there is a scope attached to this server, attempt to authenticate.
-        let req_builder = if let Some(auth) = &server.auth {
-            req_builder.bearer_auth(
-                token
-                    .get_token(&[&auth.scope])
-                    .await
-                    .context("failed to get token")?
-                    .token
-                    .secret(),
-            )
-        } else {
-            req_builder
-        };
+/// Determine whether requests may be sent to `url` under the configured upstream host allow-list.
+fn host_allowed(allowed: Option<&[String]>, url: &Url) -> bool {
+    let Some(allowed) = allowed else {
+        return true;
+    };
 
-        let req = req_builder.send().await.context("failed to send request")?;
+    url.host_str()
+        .is_some_and(|host| allowed.iter().any(|a| a.eq_ignore_ascii_case(host)))
+}
 
-        // Check to see if the server returned a successful status code. If it didn't, continue on to the next server.
-        trace!("{}: {}", url, req.status());
-        if !req.status().is_success() {
-            continue;
-        }
+/// Build the client for requests to upstream servers, applying any DNS settings, timeouts, and the
+/// upstream host allow-list.
+///
+/// If `server` is provided, its timeouts take precedence over the global ones.
+fn http_client(
+    config: &AppConfig,
+    server: Option<&ConfigServer>,
+    resolver: Option<&Arc<dns::CachingResolver>>,
+) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(
+            config
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+        )
+        .pool_idle_timeout(Duration::from_secs(
+            config
+                .pool_idle_timeout
+                .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+        ));
 
-        // Forward out the full response from the upstream server, including headers and status code.
-        let mut response_builder = Response::builder().status(req.status());
+    let connect_timeout = server
+        .and_then(|s| s.connect_timeout)
+        .or(config.connect_timeout);
+    if let Some(timeout) = connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(timeout));
+    }
 
-        if let Some(headers) = response_builder.headers_mut() {
-            *headers = req.headers().clone();
+    let read_timeout = server.and_then(|s| s.read_timeout).or(config.read_timeout);
+    if let Some(timeout) = read_timeout {
+        builder = builder.read_timeout(Duration::from_secs(timeout));
+    }
 
-            // Insert an additional header describing where this symbol originated from.
-            headers.insert(UPSTREAM_SOURCE, HeaderValue::from_static("server"));
-            headers.insert(
-                UPSTREAM_SERVER,
-                HeaderValue::from_str(server.url.as_str()).unwrap(),
-            );
-        }
+    if let Some(dns) = &config.dns {
+        for (host, addrs) in &dns.overrides {
+            // N.B: A port of 0 causes the port of the request URL to be used.
+            let addrs = addrs
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, 0))
+                .collect::<Vec<_>>();
 
-        // Now, we'll want to do one of two things depending on if caching is enabled:
-        // If enabled, we will split the response stream into two and direct one end to the storage account,
-        // and the other end to the requesting user. This also has the side effect of throttling the user's
-        // download speed if our upload is slower, but this is the cost to pay to keep things out of memory.
-        //
-        // If disabled, we can simply direct the response stream back out to the requester directly.
-        let stream: Pin<Box<dyn Stream<Item = _> + Send>> = if let Some(cache) = &config.cache {
-            let mut stream = req.bytes_stream();
-            let (tx, rx) = tokio::sync::mpsc::channel(32);
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+    }
 
-            // Clone the cache into the task below.
-            let cache = cache.clone();
-
-            tokio::spawn(async move {
-                match cache {
-                    ConfigCache::Azure(cache) => {
-                        let cred = if let Some(key) = &cache.key {
-                            StorageCredentials::access_key(&cache.storage_account, key.clone())
-                        } else {
-                            StorageCredentials::token_credential(token.clone())
-                        };
-
-                        // Wrap the client in an `Option`. If an error occurs, the client will be set to `None` and
-                        // mirroring will be aborted.
-                        let mut client = Some(
-                            azure_storage_blobs::prelude::ClientBuilder::new(
-                                &cache.storage_account,
-                                cred,
-                            )
-                            .blob_client(
-                                &cache.storage_container,
-                                format!("{name1}/{hash}/{name2}"),
-                            ),
-                        );
+    if let Some(resolver) = resolver {
+        builder = builder.dns_resolver(resolver.clone());
+    }
 
-                        let mut block_list = BlockList::default();
-                        while let Some(chunk) = stream.next().await {
-                            let chunk = chunk.context("failed to read chunk")?;
-
-                            // N.B: `block_id` must be <= 64 bytes in size.
-                            // Use a randomly generated ID to avoid conflicts.
-                            let block_id = format!("{}", Uuid::new_v4());
-
-                            if let Err(e) = match &client {
-                                Some(client) => client
-                                    .put_block(block_id.clone(), chunk.clone())
-                                    .await
-                                    .map(|_| ()),
-                                None => Ok(()),
-                            } {
-                                error!(
-                                    "{:?}",
-                                    anyhow::Error::new(e)
-                                        .context("failed to put block while mirroring symbol")
-                                );
+    // N.B: Refuse to follow redirects to hosts outside of the allow-list, as they could otherwise
+    // be used to exfiltrate requests to arbitrary hosts.
+    if let Some(allowed) = config.allowed_upstream_hosts.clone() {
+        builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if host_allowed(Some(&allowed), attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        }));
+    }
 
-                                // If an error occurs, set the client to `None` to abort mirroring.
-                                client = None;
-                            }
+    // N.B: Responses compressed with gzip or deflate are transparently decompressed (and their
+    // `Content-Encoding` and `Content-Length` removed), so that the cache only ever sees the raw
+    // symbol.
+    builder = builder.gzip(true).deflate(true);
 
-                            block_list
-                                .blocks
-                                .push(BlobBlockType::new_uncommitted(block_id));
+    builder.build().context("failed to build http client")
+}
 
-                            // Forward the data on to the original requesting client.
-                            // Ignore errors since we want mirroring to continue even if the client
-                            // closes their connection.
-                            let _ = tx.send(Ok(chunk)).await;
-                        }
+/// Send a request to `server`, over its Unix domain socket if it has one.
+async fn send_request(
+    config: &AppConfig,
+    server: &ConfigServer,
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<reqwest::Response> {
+    match &server.unix_socket {
+        #[cfg(unix)]
+        Some(socket) => {
+            let timeouts = unix::Timeouts {
+                connect: server
+                    .connect_timeout
+                    .or(config.connect_timeout)
+                    .map(Duration::from_secs),
+                read: server
+                    .read_timeout
+                    .or(config.read_timeout)
+                    .map(Duration::from_secs),
+            };
 
-                        // Finalize the blob upload if mirroring has not been aborted.
-                        //
-                        // N.B: If multiple instances of this server attempt to upload the same blob at the same
-                        // time, the last one wins. Unfortunately we cannot acquire a lease on a blob that has not
-                        // been created so we cannot prevent this race.
-                        if let Some(client) = client {
-                            let mut meta = Metadata::new();
-                            meta.insert(
-                                "UpstreamServer",
-                                form_urlencoded::byte_serialize(url.as_str().as_bytes())
-                                    .collect::<String>(),
-                            );
+            unix::send(socket, request, timeouts).await
+        }
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("unix sockets are not supported on this platform"),
+        None => Ok(request.send().await?),
+    }
+}
 
-                            if let Err(e) = client.put_block_list(block_list).metadata(meta).await {
-                                error!(
-                                    "{:?}",
-                                    anyhow::Error::new(e).context("failed to mirror symbol")
-                                );
-                            }
-                        }
+/// Determine the content type configured for a symbol named `name`, if its extension is known.
+fn known_content_type<'a>(name: &str, overrides: &'a HashMap<String, String>) -> Option<&'a str> {
+    let ext = std::path::Path::new(name)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
 
-                        Ok::<(), anyhow::Error>(())
-                    }
-                    ConfigCache::Fs(cache) => {
-                        let path = cache.path.join(format!("{name1}/{hash}/{name2}"));
+    if let Some(content_type) = overrides.get(&ext) {
+        return Some(content_type);
+    }
 
-                        let mut f = {
-                            let _ = tokio::fs::create_dir_all(path.parent().unwrap()).await;
+    Some(match ext.as_str() {
+        "pdb" => "application/x-ms-pdb",
+        "exe" | "dll" | "sys" => "application/vnd.microsoft.portable-executable",
+        "cab" => "application/vnd.ms-cab-compressed",
+        // Compressed symbols (e.g. `foo.pd_`) are stored as cabinet files.
+        _ if ext.ends_with('_') => "application/vnd.ms-cab-compressed",
+        _ => return None,
+    })
+}
 
-                            tokio::fs::File::create(path.clone()).await.ok()
-                        };
+/// Determine the content type to serve a symbol named `name` with.
+fn content_type<'a>(name: &str, overrides: &'a HashMap<String, String>) -> &'a str {
+    known_content_type(name, overrides).unwrap_or("application/octet-stream")
+}
 
-                        while let Some(chunk) = stream.next().await {
-                            let chunk = chunk.context("failed to read chunk")?;
+/// Encode a SHA-256 digest as base64, as recorded in the cache and sent in `Repr-Digest`.
+fn encode_digest(digest: Sha256) -> String {
+    base64::prelude::BASE64_STANDARD.encode(digest.finalize())
+}
 
-                            if let Err(e) = match &mut f {
-                                Some(f) => f.write_all(&chunk).await.map(|_| ()),
-                                None => Ok(()),
-                            } {
-                                error!(
-                                    "{:?}",
-                                    anyhow::Error::new(e)
-                                        .context("failed to write chunk while mirroring symbol")
-                                );
+/// Wraps a response body stream such that it is followed by a `Repr-Digest` trailer holding the
+/// SHA-256 of its contents.
+fn with_digest_trailer<S, E>(stream: S) -> impl Stream<Item = Result<Frame<Bytes>, E>> + Send
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send + 'static,
+{
+    let state = (Box::pin(stream), Some(Sha256::new()));
 
-                                f = None;
-                            }
+    futures::stream::unfold(state, |(mut stream, digest)| async move {
+        let mut digest = digest?;
 
-                            let _ = tx.send(Ok(chunk)).await;
-                        }
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                digest.update(&chunk);
+                Some((Ok(Frame::data(chunk)), (stream, Some(digest))))
+            }
+            Some(Err(e)) => Some((Err(e), (stream, None))),
+            None => {
+                let value = format!("sha-256=:{}:", encode_digest(digest));
 
-                        Ok::<(), anyhow::Error>(())
-                    }
+                let mut trailers = HeaderMap::new();
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    trailers.insert(REPR_DIGEST, value);
                 }
-            });
 
-            Box::pin(ReceiverStream::new(rx))
-        } else {
-            Box::pin(req.bytes_stream())
-        };
+                Some((Ok(Frame::trailers(trailers)), (stream, None)))
+            }
+        }
+    })
+}
 
-        // Stream out the response from the upstream server as we receive it.
-        return Ok(response_builder
-            .body(Body::from_stream(stream))
-            .context("failed to build response body")?);
-    }
+/// Wraps a response body stream such that it is aborted with an error if `deadline` elapses
+/// before the stream completes.
+fn with_deadline<S, E>(
+    stream: S,
+    deadline: Instant,
+    request_id: String,
+) -> impl Stream<Item = Result<Bytes, BoxError>> + Send
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError>,
+{
+    let state = (
+        Box::pin(stream),
+        Box::pin(tokio::time::sleep_until(deadline)),
+        false,
+    );
 
-    Ok(Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::empty())
-        .context("failed to build response body")?)
-}
+    futures::stream::unfold(state, move |(mut stream, mut sleep, expired)| {
+        let request_id = request_id.clone();
 
-/// Endpoint used by Azure to query this application's health status.
-async fn health(State(config): State<AppConfig>, headers: HeaderMap) -> Result<Response, Error> {
-    // Check to see if the request originates from Azure.
-    // https://learn.microsoft.com/en-us/azure/app-service/monitor-instances-health-check?tabs=dotnet#authentication-and-security
-    if let Some(key) = std::env::var_os("WEBSITE_AUTH_ENCRYPTION_KEY") {
-        let f = || {
-            // FIXME: According to the documentation, this header is only provided on Windows instances. Sigh.
-            let auth_token = headers
-                .get(INTERNAL_AUTH_TOKEN)
-                .context("missing internal auth token")?;
+        async move {
+            if expired {
+                return None;
+            }
 
-            let hash = {
-                let mut sha = Sha256::new();
-                sha.update(key.as_encoded_bytes());
-                let hash = sha.finalize();
+            tokio::select! {
+                chunk = stream.next() => chunk.map(|c| (c.map_err(Into::into), (stream, sleep, false))),
+                _ = &mut sleep => {
+                    error!("request {request_id} exceeded its deadline while streaming; aborting response");
 
-                base64::prelude::BASE64_STANDARD.encode(hash.as_slice())
-            };
+                    // N.B: Returning an error here causes the connection to be torn down, which
+                    // signals to the client that the response is incomplete.
+                    Some((Err("request deadline exceeded".into()), (stream, sleep, true)))
+                }
+            }
+        }
+    })
+}
 
-            if hash.as_bytes() == auth_token.as_bytes() {
-                Ok(())
-            } else {
-                anyhow::bail!("invalid authentication hash");
+/// Wraps a response body stream such that it is aborted with an error if `shutdown` is
+/// cancelled before the stream completes.
+fn with_cancellation<S, E>(
+    stream: S,
+    shutdown: CancellationToken,
+    request_id: String,
+) -> impl Stream<Item = Result<Bytes, BoxError>> + Send
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError>,
+{
+    let state = (Box::pin(stream), shutdown, false);
+
+    futures::stream::unfold(state, move |(mut stream, shutdown, cancelled)| {
+        let request_id = request_id.clone();
+
+        async move {
+            if cancelled {
+                return None;
             }
-        };
 
-        match (f)() {
-            // Continue on.
-            Ok(_) => (),
-            // If validation fails for any reason, just return an opaque 401 response.
-            Err(_e) => {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .body(Body::empty())
-                    .context("failed to build response body")?)
+            tokio::select! {
+                chunk = stream.next() => chunk.map(|c| (c.map_err(Into::into), (stream, shutdown, false))),
+                _ = shutdown.cancelled() => {
+                    warn!("request {request_id} cancelled by shutdown while streaming; aborting response");
+                    Some((Err("server shutting down".into()), (stream, shutdown, true)))
+                }
+            }
+        }
+    })
+}
+
+/// Primary endpoint used to proxy a symbol file from the configured upstream server.
+///
+/// If a request timeout is configured, the entire request (including streaming the body)
+/// is bounded by it.
+async fn symbol(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    Path((name1, hash, name2)): Path<(String, String, String)>,
+) -> Result<Response, Error> {
+    // Refuse paths that could escape the cache directory or alter the upstream URL, before doing
+    // any work with them.
+    if !valid_symbol_path(&name1, &hash, &name2) {
+        debug!("refusing to serve invalid symbol path {name1}/{hash}/{name2}");
+
+        return Ok((StatusCode::BAD_REQUEST, "invalid symbol path").into_response());
+    }
+
+    // Refuse to proxy files that are not symbols, if restricted.
+    if !extension_allowed(&state.config.allowed_extensions, &name2) {
+        debug!("refusing to serve {name2}, as its extension is not allowed");
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "the requested file type is not served by this proxy",
+        )
+            .into_response());
+    }
+
+    let request_id = request_id
+        .header_value()
+        .to_str()
+        .unwrap_or_default()
+        .to_string();
+    let deadline = state
+        .config
+        .request_timeout
+        .map(|t| Instant::now() + Duration::from_secs(t));
+
+    let stats = state.stats.clone();
+    let request = stats.begin_request();
+    let symbol_source_header = state.config.symbol_source_header;
+    let accepts_trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("trailers"));
+    let digest_trailer = state.config.content_digest && accepts_trailers;
+    let limiters = state.global_limiter.iter().cloned().collect::<Vec<_>>();
+    let shutdown = state.shutdown.clone();
+    let fut = fetch_symbol(state, headers, name1, hash, name2);
+
+    // Abandon the request if the server is shutting down and its grace period has elapsed.
+    let fut = async {
+        tokio::select! {
+            response = fut => response,
+            _ = shutdown.cancelled() => {
+                warn!("request {request_id} cancelled by shutdown");
+
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::empty())
+                    .context("failed to build response body")
+                    .map_err(Error::from)
+            }
+        }
+    };
+    let response = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, fut).await {
+            Ok(response) => response,
+            Err(_) => {
+                error!(
+                    "request {request_id} exceeded its deadline before a response was available"
+                );
+                metrics::counter!("requests_total", "status" => "504").increment(1);
+
+                return Ok(Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .body(Body::empty())
+                    .context("failed to build response body")?);
+            }
+        },
+        None => fut.await,
+    };
+
+    let mut response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            metrics::counter!("requests_total", "status" => "500").increment(1);
+            return Err(e);
+        }
+    };
+    metrics::counter!("requests_total", "status" => response.status().as_str().to_owned())
+        .increment(1);
+    if symbol_source_header {
+        if let Some(source) = symbol_source(&response) {
+            response.headers_mut().insert(SYMBOL_SOURCE, source);
+        }
+    }
+
+    // Digests are sent as a trailer unless one is already known (e.g. for cache hits). Trailers
+    // can only be sent on a chunked body, so the length is dropped.
+    let digest_trailer = digest_trailer
+        && response.status().is_success()
+        && !response.headers().contains_key(REPR_DIGEST);
+    if digest_trailer {
+        let headers = response.headers_mut();
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(header::TRAILER, HeaderValue::from_static(REPR_DIGEST));
+    }
+
+    Ok(response.map(|body| {
+        let stream = body.into_data_stream().inspect_ok(move |chunk| {
+            // N.B: The request remains in flight until its body has been fully sent (or dropped).
+            let _ = &request;
+
+            metrics::counter!("bytes_served_total").increment(chunk.len() as u64);
+            Stats::add(&stats.bytes_served, chunk.len() as u64);
+        });
+        let stream = with_cancellation(
+            throttle::throttle(stream, limiters),
+            shutdown,
+            request_id.clone(),
+        );
+
+        let stream: Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send>> = match deadline {
+            Some(deadline) => Box::pin(with_deadline(stream, deadline, request_id)),
+            None => Box::pin(stream),
+        };
+
+        if digest_trailer {
+            Body::new(StreamBody::new(with_digest_trailer(stream)))
+        } else {
+            Body::from_stream(stream)
+        }
+    }))
+}
+
+/// Handler for `HEAD` requests, which check whether a symbol exists without downloading it.
+///
+/// The cache is consulted (unless `disable_cache_reads` is set) but never written to, and then
+/// each upstream server is sent a `HEAD` request in turn. The status and headers of the first
+/// server to have the symbol are forwarded.
+async fn symbol_head(
+    State(state): State<AppState>,
+    Path((name1, hash, name2)): Path<(String, String, String)>,
+) -> Result<Response, Error> {
+    let config = &state.config;
+    if !valid_symbol_path(&name1, &hash, &name2)
+        || !extension_allowed(&config.allowed_extensions, &name2)
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::empty())
+            .context("failed to build response body")?);
+    }
+
+    let cache = config
+        .cache
+        .as_ref()
+        .filter(|_| !config.disable_cache_reads);
+    if let Some(cache) = cache {
+        let cache_path = cache_key(config, &name1, &hash, &name2);
+        if let Some(size) = cache::size(cache, state.token.clone(), &cache_path).await? {
+            let mut builder = Response::builder().header(UPSTREAM_SOURCE, "cache").header(
+                header::CONTENT_TYPE,
+                content_type(&name2, &config.content_types),
+            );
+            if let Some(size) = size {
+                builder = builder.header(header::CONTENT_LENGTH, size);
+            }
+
+            return Ok(builder
+                .body(Body::empty())
+                .context("failed to build response body")?);
+        }
+    }
+
+    for idx in state.server_order() {
+        let server = &config.servers[idx];
+        if !head_supported(server) {
+            continue;
+        }
+
+        let url = symbol_url(server, &name1, &hash, &name2)?;
+        if !host_allowed(config.allowed_upstream_hosts.as_deref(), &url) {
+            continue;
+        }
+
+        match head_request(&state, idx, &url).await {
+            Ok(res) if res.status().is_success() => {
+                let mut builder = Response::builder().status(res.status());
+                if let Some(headers) = builder.headers_mut() {
+                    *headers = forwarded_headers(config, server, &url, &name2, res.headers());
+                }
+
+                return Ok(builder
+                    .body(Body::empty())
+                    .context("failed to build response body")?);
+            }
+            Ok(res) => trace!("{url}: {}", res.status()),
+            Err(e) => warn!(
+                "{:?}",
+                e.context(format!("failed to send request to {url}"))
+            ),
+        }
+    }
+
+    let status = not_found_response(config)?.status();
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .context("failed to build response body")?)
+}
+
+/// Fallback for requests that do not match any route, which are most likely malformed symbol
+/// requests (e.g. missing a path segment).
+async fn malformed(uri: axum::http::Uri) -> Response {
+    debug!("malformed symbol request: {uri}");
+
+    (
+        StatusCode::BAD_REQUEST,
+        "malformed symbol request: expected a path of the form `/<name>/<hash>/<name>`",
+    )
+        .into_response()
+}
+
+/// Determine whether `component` is safe to use as a single segment of an upstream URL or cache
+/// path: that is, it is not empty and cannot escape its directory.
+fn valid_component(component: &str) -> bool {
+    let lower = component.to_ascii_lowercase();
+
+    // N.B: A `:` would let a leading component (e.g. `http:evil.com`) be parsed as the scheme of
+    // an absolute URL when joined onto the server's URL.
+    !component.is_empty()
+        && component != "."
+        && component != ".."
+        && !component.contains(['/', '\\', '\0', ':'])
+        // N.B: Components have already been percent-decoded once, so these would only appear if
+        // the client encoded them twice, hoping that something downstream decodes them again.
+        && !lower.contains("%2f")
+        && !lower.contains("%5c")
+        && !lower.contains("%00")
+}
+
+/// Determine whether the components of a symbol request are valid (see [`valid_component`]).
+fn valid_symbol_path(name1: &str, hash: &str, name2: &str) -> bool {
+    [name1, hash, name2].into_iter().all(valid_component)
+}
+
+/// Determine whether a file named `name` may be served under the configured extension allow-list.
+///
+/// Compressed forms of allowed extensions (e.g. `pd_` for `pdb`) are also allowed.
+fn extension_allowed(allowed: &[String], name: &str) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+
+    let Some(ext) = std::path::Path::new(name).extension() else {
+        return false;
+    };
+    let ext = ext.to_string_lossy().to_lowercase();
+
+    allowed.iter().any(|a| {
+        let a = a.trim_start_matches('.').to_lowercase();
+
+        a == ext
+            || ext
+                .strip_suffix('_')
+                .is_some_and(|prefix| a.len() == ext.len() && a.starts_with(prefix))
+    })
+}
+
+/// Determine the value of the `X-Symbol-Source` header for a response: the host of the upstream
+/// server that served it, or where else it came from (e.g. "cache").
+fn symbol_source(response: &Response) -> Option<HeaderValue> {
+    let headers = response.headers();
+
+    match headers.get(UPSTREAM_SOURCE)?.to_str().ok()? {
+        "server" => {
+            let server = Url::parse(headers.get(UPSTREAM_SERVER)?.to_str().ok()?).ok()?;
+            HeaderValue::from_str(server.host_str()?).ok()
+        }
+        source => HeaderValue::from_str(source).ok(),
+    }
+}
+
+/// Build a response serving a symbol from the cache.
+fn cache_response(hit: cache::CacheHit, content_type: &str) -> anyhow::Result<Response> {
+    let mut builder = Response::builder()
+        .header(UPSTREAM_SOURCE, "cache")
+        .header(X_CACHE, "HIT")
+        .header(header::CONTENT_TYPE, content_type);
+
+    if let Some(len) = hit.content_length {
+        builder = builder.header(header::CONTENT_LENGTH, len.to_string());
+    }
+
+    if let Some(encoding) = hit.content_encoding {
+        builder = builder.header(header::CONTENT_ENCODING, encoding);
+    }
+
+    // N.B: The recorded digest is of the decoded symbol, so it only describes unencoded bodies.
+    if let (Some(sha256), None) = (&hit.sha256, hit.content_encoding) {
+        builder = builder.header(REPR_DIGEST, format!("sha-256=:{sha256}:"));
+    }
+
+    builder
+        .status(StatusCode::OK)
+        .body(hit.body)
+        .context("failed to build response body")
+}
+
+/// Parse the value of a `Range` header against a body of `len` bytes.
+///
+/// Returns `None` if the header should be ignored (e.g. it is malformed or requests multiple
+/// ranges), or `Some(None)` if the range cannot be satisfied.
+fn byte_range(value: &str, len: usize) -> Option<Option<std::ops::Range<usize>>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // A suffix range, selecting the last `end` bytes.
+        let suffix = end.parse::<usize>().ok()?;
+        len.saturating_sub(suffix)..len
+    } else {
+        let start = start.parse::<usize>().ok()?;
+        let end = match end {
+            "" => len,
+            end => end.parse::<usize>().ok()?.saturating_add(1).min(len),
+        };
+
+        start..end
+    };
+
+    Some(Some(range).filter(|r| r.start < r.end))
+}
+
+/// Build a response serving the requested range of a fully downloaded symbol.
+fn range_response(
+    mut builder: axum::http::response::Builder,
+    body: Vec<u8>,
+    range: &str,
+) -> anyhow::Result<Response> {
+    let len = body.len();
+    if let Some(headers) = builder.headers_mut() {
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+
+    let response = match byte_range(range, len) {
+        // The range could not be understood, so serve the entire symbol.
+        None => builder
+            .header(header::CONTENT_LENGTH, len)
+            .body(Body::from(body)),
+        Some(None) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+            .header(header::CONTENT_LENGTH, 0)
+            .body(Body::empty()),
+        Some(Some(r)) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{len}", r.start, r.end - 1),
+            )
+            .header(header::CONTENT_LENGTH, r.len())
+            .body(Body::from(body[r].to_vec())),
+    };
+
+    response.context("failed to build response body")
+}
+
+/// Determine whether the client accepts the given content encoding.
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| {
+            let mut parts = v.split(';').map(str::trim);
+            parts
+                .next()
+                .is_some_and(|e| e.eq_ignore_ascii_case(encoding))
+                && !parts.any(|p| {
+                    p.strip_prefix("q=")
+                        .is_some_and(|q| q.parse::<f32>() == Ok(0.0))
+                })
+        })
+}
+
+/// Resolve a symbol from the cache or the configured upstream servers.
+async fn fetch_symbol(
+    state: AppState,
+    headers: HeaderMap,
+    name1: String,
+    hash: String,
+    name2: String,
+) -> Result<Response, Error> {
+    let config = &state.config;
+    let token = &state.token;
+    let stats = &state.stats;
+    let received = Instant::now();
+
+    Stats::add(&stats.requests, 1);
+
+    // Requests matching an exclusion pattern bypass the cache entirely.
+    let symbol_path = format!("{name1}/{hash}/{name2}");
+    let excluded = config.cache_exclude.iter().any(|p| p.matches(&symbol_path));
+    let cache_path = cache_key(config, &name1, &hash, &name2);
+    let cache = config.cache.as_ref().filter(|_| !excluded);
+    let accept_zstd = accepts_encoding(&headers, "zstd");
+    let range = headers
+        .get(header::RANGE)
+        .filter(|_| config.buffer_ranged_requests)
+        .and_then(|v| v.to_str().ok());
+    // Otherwise, ranges are passed through to upstream servers, which may serve them directly.
+    let forwarded_range = headers
+        .get(header::RANGE)
+        .filter(|_| !config.buffer_ranged_requests)
+        .cloned();
+
+    // If configured to, attempt the local store before anything else.
+    let local = config.local_store.as_ref().map(|path| {
+        ConfigCache::Fs(ConfigFsCache {
+            path: path.clone(),
+            compress_cache: false,
+            write_buffer_size: None,
+            index: false,
+            min_free_bytes: None,
+        })
+    });
+    if config.local_first {
+        if let Some(response) =
+            local_lookup(&state, local.as_ref(), &symbol_path, &name2, accept_zstd).await?
+        {
+            return Ok(response);
+        }
+    }
+
+    // Attempt the cache first, if one is set.
+    let mut flight = None;
+    if let Some(cache) = cache {
+        if let Some(hit) = cache_lookup(&state, cache, &cache_path, accept_zstd).await? {
+            Stats::add(&stats.cache_hits, 1);
+            metrics::counter!("cache_hits_total").increment(1);
+            return Ok(cache_response(
+                hit,
+                content_type(&name2, &config.content_types),
+            )?);
+        }
+
+        metrics::counter!("cache_misses_total").increment(1);
+
+        // If the symbol is already being downloaded, wait for it to be mirrored rather than
+        // downloading it again. This is pointless if it cannot then be served from the cache.
+        let join = (!config.disable_cache_reads).then(|| state.flights.join(&cache_path));
+        match join {
+            None => {}
+            Some(coalesce::Flight::Leader(guard)) => flight = Some(guard),
+            Some(coalesce::Flight::Follower(done)) => {
+                debug!("{cache_path}: waiting on an in-flight download");
+                Stats::add(&stats.coalesced, 1);
+                metrics::counter!("requests_coalesced_total").increment(1);
+
+                coalesce::wait(done).await;
+                if let Some(hit) = cache_lookup(&state, cache, &cache_path, accept_zstd).await? {
+                    Stats::add(&stats.cache_hits, 1);
+                    return Ok(cache_response(
+                        hit,
+                        content_type(&name2, &config.content_types),
+                    )?);
+                }
+
+                // The download failed (or was not mirrored), so fall through and try ourselves.
+                debug!("{cache_path}: in-flight download was not mirrored; fetching it");
+            }
+        }
+    }
+
+    // Bound the number of requests fetching from upstream at once, so that a burst of misses
+    // cannot exhaust memory or file handles. The permit is held until the symbol has been sent.
+    let fetch_permit = match &state.fetch_permits {
+        Some(FetchPermits(permits)) => {
+            let wait = Duration::from_secs(
+                config
+                    .fetch_queue_timeout
+                    .unwrap_or(DEFAULT_FETCH_QUEUE_TIMEOUT),
+            );
+
+            match tokio::time::timeout(wait, permits.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => Some(Arc::new(permit)),
+                _ => {
+                    warn!("fetch limit reached; rejecting request for {symbol_path}");
+                    Stats::add(&stats.misses, 1);
+
+                    return Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header(header::RETRY_AFTER, wait.as_secs())
+                        .body(Body::empty())
+                        .context("failed to build response body")?);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Track how often this symbol has been requested, to determine whether it is worth caching.
+    let hot = match (cache, &state.request_counts, &config.cache_threshold) {
+        (Some(_), Some(counts), Some(threshold)) => {
+            record_request(counts, &cache_path, threshold.requests)
+        }
+        _ => true,
+    };
+
+    // Track the number of upstream servers that could not be reached at all.
+    let mut unreachable = 0;
+
+    let mut order = state.server_order();
+    if config.race_servers {
+        order = race_servers(&state, order, &name1, &hash, &name2).await;
+    }
+
+    'servers: for idx in order {
+        let server = &config.servers[idx];
+        let url = symbol_url(server, &name1, &hash, &name2)?;
+
+        // Never send requests (or credentials) to a host outside of the allow-list.
+        if !host_allowed(config.allowed_upstream_hosts.as_deref(), &url) {
+            error!(
+                "refusing to send request to {url}, as its host is not in `allowed_upstream_hosts`"
+            );
+            continue;
+        }
+
+        // Skip servers that recently reported the symbol as missing.
+        if let Some(negative) = &state.negative_cache {
+            let key = (idx, cache_path.clone());
+            let mut negative = negative.lock().unwrap();
+
+            match negative.get(&key).copied() {
+                Some(expiry) if expiry > Instant::now() => {
+                    trace!("{url}: recently missing; skipping");
+                    continue;
+                }
+                Some(_) => {
+                    negative.pop(&key);
+                    metrics::gauge!("negative_cache_entries").set(negative.len() as f64);
+                }
+                None => {}
+            }
+        }
+
+        // Dispatch a reqwest request to upstream, and serve the response.
+        // https://github.com/tokio-rs/axum/blob/680cdcba7cfa0b4fb37aba0c129ab6e4379bae3b/examples/reqwest-response/src/main.rs#L53-L68
+        let client = &state.server_clients[idx];
+        let timeout = server.timeout.or(config.upstream_timeout);
+
+        // Whether the token has already been refreshed after being rejected by this server.
+        let mut refreshed = false;
+        let mut attempt = 0;
+
+        let body = server
+            .body_template
+            .as_deref()
+            .map(|t| expand_body_template(t, &name1, &hash, &name2));
+
+        let req = loop {
+            let mut req_builder =
+                upstream_request(client, server, token.as_ref(), &url, body.as_deref()).await?;
+            if let Some(timeout) = timeout {
+                req_builder = req_builder.timeout(Duration::from_secs(timeout));
+            }
+            if let Some(range) = &forwarded_range {
+                req_builder = req_builder.header(header::RANGE, range.clone());
+            }
+
+            let sent = Instant::now();
+            let req = match send_request(config, server, req_builder).await {
+                Ok(req) => {
+                    metrics::histogram!("upstream_response_seconds", "server" => server.url.to_string())
+                        .record(sent.elapsed().as_secs_f64());
+                    req
+                }
+                Err(e) => {
+                    // Log the failure and move on to the next server.
+                    let timed_out = e
+                        .downcast_ref::<reqwest::Error>()
+                        .is_some_and(|e| e.is_timeout());
+                    let e = e.context(format!("failed to send request to {url}"));
+                    if timed_out {
+                        warn!("{e:?}");
+                    } else {
+                        error!("{e:?}");
+                    }
+                    stats.record_error(idx, &e);
+                    metrics::counter!(
+                        "upstream_requests_total",
+                        "server" => server.url.to_string(),
+                        "result" => "failure"
+                    )
+                    .increment(1);
+
+                    unreachable += 1;
+                    continue 'servers;
+                }
+            };
+
+            // The cached token may have expired between acquiring and using it. If the server rejects it,
+            // force a fresh token and retry the server once.
+            if req.status() == StatusCode::UNAUTHORIZED && server.auth.is_some() && !refreshed {
+                warn!("{url} rejected our token; refreshing it and retrying");
+                if let Err(e) = token.clear_cache().await {
+                    warn!(
+                        "{:?}",
+                        anyhow::Error::new(e).context("failed to clear token cache")
+                    );
+                }
+
+                refreshed = true;
+                continue;
+            }
+
+            // Retry the server if it returned a retryable status, backing off between attempts.
+            if !req.status().is_success()
+                && server.status_action(req.status()) == StatusAction::Retry
+                && attempt < server.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+            {
+                let delay = Duration::from_millis(500 << attempt.min(6));
+                warn!(
+                    "{url} returned {}, retrying in {}ms",
+                    req.status(),
+                    delay.as_millis()
+                );
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+
+            break req;
+        };
+
+        // Check to see if the server returned a successful status code. If it didn't, handle it as configured for
+        // this server (continuing on to the next server by default).
+        trace!("{}: {}", url, req.status());
+        stats.record_status(idx, req.status());
+        if !req.status().is_success() {
+            metrics::counter!(
+                "upstream_requests_total",
+                "server" => server.url.to_string(),
+                "result" => "failure"
+            )
+            .increment(1);
+
+            match server.status_action(req.status()) {
+                StatusAction::Miss => {
+                    // Classic symbol stores may hold the symbol in another form.
+                    if server.compressed_fallback && req.status() == StatusCode::NOT_FOUND {
+                        match compressed::fetch(&state, idx, &name1, &hash, &name2).await {
+                            Ok(Some(found)) => {
+                                return Ok(compressed::respond(
+                                    &state,
+                                    idx,
+                                    cache,
+                                    hot,
+                                    &cache_path,
+                                    &name2,
+                                    found,
+                                )
+                                .await?);
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                let e = e.context(format!(
+                                    "failed to fetch the compressed form of {url}"
+                                ));
+                                error!("{e:?}");
+                                stats.record_error(idx, &e);
+                            }
+                        }
+                    }
+
+                    // Remember that the symbol is missing from this server, if configured to.
+                    let ttl = server.negative_ttl.or(config.negative_ttl);
+                    if let (Some(negative), Some(ttl)) = (&state.negative_cache, ttl) {
+                        let mut negative = negative.lock().unwrap();
+                        negative.put(
+                            (idx, cache_path.clone()),
+                            Instant::now() + Duration::from_secs(ttl),
+                        );
+                        metrics::gauge!("negative_cache_entries").set(negative.len() as f64);
+                    }
+                }
+                // Retries have been exhausted, so the server is effectively unreachable.
+                StatusAction::Retry => unreachable += 1,
+                StatusAction::Fail => {
+                    error!("{url} returned {}; failing request", req.status());
+                    Stats::add(&stats.misses, 1);
+
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::empty())
+                        .context("failed to build response body")?);
+                }
+            }
+
+            continue;
+        }
+
+        metrics::counter!(
+            "upstream_requests_total",
+            "server" => server.url.to_string(),
+            "result" => "success"
+        )
+        .increment(1);
+
+        // Forward out the full response from the upstream server, including headers and status code.
+        let mut response_builder = Response::builder().status(req.status());
+
+        if let Some(headers) = response_builder.headers_mut() {
+            *headers = forwarded_headers(config, server, &url, &name2, req.headers());
+        }
+
+        // Track the download until the upstream body has been fully consumed (or dropped).
+        let download = stats.begin_download();
+        let mut timer = status::TransferTimer::new(url.clone(), &server.url, received);
+
+        // Upstream did not serve the requested range itself, so it must be carved out of the full symbol.
+        let range = range.filter(|_| req.status() == StatusCode::OK);
+
+        // The length upstream declared for the symbol, which a complete mirror must match.
+        let expected_len = req.content_length();
+
+        // Whether the body is still encoded (e.g. it was received over a Unix domain socket, which
+        // bypasses decompression), in which case it is not the symbol itself.
+        let encoded = req
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|v| v != "identity");
+
+        // Whether upstream served only part of the symbol, in response to a passed-through range.
+        let partial = req.status() == StatusCode::PARTIAL_CONTENT;
+
+        // Identity verification needs the entire symbol up front, so it is only done for symbols of
+        // a bounded size.
+        let verify = config.verify_identity
+            && expected_len.is_some_and(|l| {
+                l <= config
+                    .verify_identity_max_size
+                    .unwrap_or(DEFAULT_VERIFY_IDENTITY_MAX_SIZE)
+            });
+
+        // If configured to, download the full response up front so that it can be sent with a fixed length.
+        // This trades latency and memory for compatibility with clients that mishandle streamed bodies.
+        // N.B: Ranged responses need the entire symbol up front, too.
+        let buffer = config.buffer_full_response || range.is_some() || verify;
+        if config.verify_identity && !buffer {
+            debug!("not verifying the identity of {url}, as it is too large or of unknown size");
+        }
+
+        let upstream: Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>> = if buffer {
+            let body = match req.bytes().await {
+                Ok(body) => body,
+                Err(e) => {
+                    // Log the failure and move on to the next server.
+                    let e = anyhow::Error::new(e)
+                        .context(format!("failed to read response body from {url}"));
+                    error!("{e:?}");
+                    stats.record_error(idx, &e);
+
+                    unreachable += 1;
+                    continue;
+                }
+            };
+
+            #[cfg(feature = "verify-identity")]
+            if config.verify_identity {
+                if let Some(id) = identity::identity(&name2, &body) {
+                    if !id.eq_ignore_ascii_case(&hash) {
+                        // N.B: Never serve or cache a file that isn't the requested symbol.
+                        error!(
+                            "{url} returned {name2} with identity {id}, but {hash} was requested; treating it as missing"
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(headers) = response_builder.headers_mut() {
+                headers.remove(header::TRANSFER_ENCODING);
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+            }
+
+            Box::pin(futures::stream::once(futures::future::ready(Ok(body))))
+        } else if req.status() == StatusCode::OK
+            && req
+                .headers()
+                .get(header::ACCEPT_RANGES)
+                .is_some_and(|v| v == "bytes")
+        {
+            // The server supports ranges, so an interrupted download can be resumed rather
+            // than failing the request.
+            let resume = resume::Resume {
+                state: state.clone(),
+                idx,
+                url: url.clone(),
+                body: body.clone(),
+                timeout: timeout.map(Duration::from_secs),
+                attempts: server
+                    .max_resume_attempts
+                    .unwrap_or(DEFAULT_MAX_RESUME_ATTEMPTS),
+            };
+
+            Box::pin(resume::resumable(Box::pin(req.bytes_stream()), resume))
+        } else {
+            Box::pin(req.bytes_stream())
+        };
+
+        // Some stores respond successfully with an empty body as a marker for symbols they do not
+        // actually have. Serving (or caching) those breaks debuggers, so treat them as missing.
+        let mut upstream = upstream.peekable();
+        if !config.allow_empty_symbols {
+            let empty = match Pin::new(&mut upstream).peek().await {
+                None => true,
+                Some(Ok(chunk)) => chunk.is_empty(),
+                Some(Err(_)) => false,
+            };
+
+            if empty {
+                warn!("{url} returned an empty symbol; treating it as missing");
+                continue;
+            }
+        }
+
+        let upstream = upstream.inspect(move |chunk| {
+            let _ = &download;
+
+            if let Ok(chunk) = chunk {
+                timer.chunk(chunk.len());
+            }
+        });
+        let upstream = Box::pin(throttle::throttle(
+            upstream,
+            state.server_limiters[idx].iter().cloned().collect(),
+        ));
+
+        // Now, we'll want to do one of two things depending on if caching is enabled:
+        // If enabled, we will split the response stream into two and direct one end to the storage account,
+        // and the other end to the requesting user. This also has the side effect of throttling the user's
+        // download speed if our upload is slower, but this is the cost to pay to keep things out of memory.
+        //
+        // If disabled, we can simply direct the response stream back out to the requester directly.
+        //
+        // If mirroring is limited and no permit is available, the symbol is served without mirroring
+        // rather than holding up the client while waiting for one.
+        let mirror = match cache {
+            // Partial content (from a passed-through range) is not the whole symbol.
+            Some(_) if partial => None,
+            // Encoded bodies are forwarded to the client as-is, but must not pollute the cache.
+            Some(_) if encoded => {
+                debug!("{cache_path} was served with a `Content-Encoding`; not mirroring it");
+                None
+            }
+            Some(cache) if !mirror_allowed(&state, cache, &cache_path, hot, expected_len) => None,
+            Some(cache) => mirror_permit(&state, &cache_path)
+                .ok()
+                .map(|permit| (cache, permit)),
+            None => None,
+        };
+
+        // Begin writing the symbol up front, so that an unavailable cache is dealt with before the
+        // response is sent.
+        let mirror = match mirror {
+            Some((cache, permit)) => {
+                match cache::CacheWriter::create(cache, token.clone(), &cache_path).await {
+                    Ok(writer) => Some((writer, permit)),
+                    Err(e) => {
+                        let e =
+                            e.context(format!("the cache is unavailable to mirror {cache_path}"));
+                        Stats::add(&stats.mirrors_aborted, 1);
+
+                        match config.cache_unavailable {
+                            ConfigCacheUnavailable::Fail => return Err(Error(e)),
+                            ConfigCacheUnavailable::StreamOnly => {
+                                warn!("{:?}", e.context("serving the symbol without caching it"));
+                            }
+                            ConfigCacheUnavailable::RetryLater => {
+                                warn!(
+                                    "{:?}",
+                                    e.context(
+                                        "serving the symbol without caching it; retrying later"
+                                    )
+                                );
+
+                                // N.B: Reserve a place in the queue up front, so that the number of
+                                // pending retries is bounded by its size.
+                                match state.prewarm.clone().try_reserve_owned() {
+                                    Ok(slot) => {
+                                        let path = cache_path.clone();
+                                        let stats = stats.clone();
+
+                                        tokio::spawn(async move {
+                                            tokio::time::sleep(CACHE_RETRY_DELAY).await;
+
+                                            Stats::add(&stats.prewarm_queued, 1);
+                                            slot.send(path);
+                                        });
+                                    }
+                                    Err(_) => {
+                                        warn!("prewarm queue is full; not retrying {cache_path}");
+                                        Stats::add(&stats.prewarm_dropped, 1);
+                                    }
+                                }
+                            }
+                        }
+
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        // Requests waiting on this download are served from the cache, so there is no point in
+        // holding them up if the symbol will not be mirrored.
+        if mirror.is_none() {
+            flight = None;
+        }
+
+        let stream: Pin<Box<dyn Stream<Item = _> + Send>> = if let Some((writer, permit)) = mirror {
+            let mut stream = upstream;
+            let flight = flight.take();
+            let fetch_permit = fetch_permit.clone();
+            let (tx, rx) = tokio::sync::mpsc::channel(32);
+
+            let stats = stats.clone();
+
+            // Determine the signature the symbol must begin with in order to be mirrored.
+            let signature = config
+                .verify_signatures
+                .as_ref()
+                .and_then(|v| expected_signature(&name2, &v.extensions));
+
+            let finish_on_disconnect = config.finish_cache_on_client_disconnect;
+            let content_digest = config.content_digest;
+            let mut sink =
+                spill::ClientSink::new(tx, config.spill_to_disk.then(|| config.temp_dir()));
+            let max_size = config.max_cache_file_size;
+            let shutdown = state.shutdown.clone();
+            let index = state.cache_index().cloned();
+
+            state.mirrors.spawn(cache_path.clone(), async move {
+                // Hold on to the mirror permit (if any) and release coalesced requests once
+                // mirroring completes.
+                let _permit = permit;
+                let _fetch_permit = fetch_permit;
+                let _flight = flight;
+
+                // Wrap the writer in an `Option`. If an error occurs, the writer will be set to `None` and
+                // mirroring will be aborted.
+                let mut writer = Some(writer);
+
+                // The leading bytes of the symbol, retained for signature verification.
+                let mut prefix = Vec::new();
+                // The number of bytes received from upstream.
+                let mut len = 0u64;
+                // The digest of the symbol, recorded alongside it in the cache.
+                let mut digest = content_digest.then(Sha256::new);
+
+                loop {
+                    let chunk = tokio::select! {
+                        chunk = stream.next() => match chunk {
+                            Some(Ok(chunk)) => chunk,
+                            Some(Err(e)) => {
+                                error!("failed to read chunk while mirroring {cache_path}: {e}");
+
+                                // Never leave a truncated symbol in the cache.
+                                if let Some(w) = writer.take() {
+                                    w.abort().await;
+                                    Stats::add(&stats.mirrors_aborted, 1);
+                                }
+
+                                // N.B: Forward the error so that the client's response is torn down
+                                // rather than appearing to complete successfully.
+                                sink.fail(e).await;
+                                return Ok(());
+                            }
+                            None => break,
+                        },
+                        // N.B: Otherwise, a disconnect is only noticed once the next chunk
+                        // arrives, which may take a while if the upstream server is slow.
+                        _ = sink.closed(), if !finish_on_disconnect => {
+                            warn!("client disconnected; cancelling download of {cache_path}");
+
+                            if let Some(w) = writer.take() {
+                                w.abort().await;
+                                Stats::add(&stats.mirrors_aborted, 1);
+                            }
+
+                            return Ok(());
+                        }
+                        _ = shutdown.cancelled() => {
+                            warn!("shutting down; abandoning mirror of {cache_path}");
+
+                            if let Some(w) = writer.take() {
+                                w.abort().await;
+                                Stats::add(&stats.mirrors_aborted, 1);
+                            }
+
+                            return Ok(());
+                        }
+                    };
+
+                    len += chunk.len() as u64;
+                    if let Some(digest) = &mut digest {
+                        digest.update(&chunk);
+                    }
+
+                    // The size may not have been known up front, so stop mirroring once it proves
+                    // to be too large.
+                    if max_size.is_some_and(|max| len > max) {
+                        if let Some(w) = writer.take() {
+                            debug!("{cache_path} exceeds `max_cache_file_size`; not mirroring it");
+                            w.abort().await;
+                            Stats::add(&stats.mirrors_aborted, 1);
+                        }
+                    }
+
+                    if let Some(signature) = &signature {
+                        let needed = signature.len().saturating_sub(prefix.len());
+                        prefix.extend_from_slice(&chunk[..needed.min(chunk.len())]);
+                    }
+
+                    if let Some(w) = &mut writer {
+                        match w.write(chunk.clone()).await {
+                            Ok(()) => {
+                                metrics::counter!("bytes_cached_total")
+                                    .increment(chunk.len() as u64);
+                                Stats::add(&stats.bytes_cached, chunk.len() as u64);
+                            }
+                            Err(e) => {
+                                error!(
+                                    "{:?}",
+                                    e.context("failed to write chunk while mirroring symbol")
+                                );
+
+                                // If an error occurs, drop the writer to abort mirroring.
+                                if let Some(w) = writer.take() {
+                                    w.abort().await;
+                                    Stats::add(&stats.mirrors_aborted, 1);
+                                }
+                            }
+                        }
+                    }
+
+                    // Forward the data on to the original requesting client.
+                    // By default, ignore errors since we want mirroring to continue even if the client
+                    // closes their connection.
+                    if !sink.send(chunk).await && !finish_on_disconnect {
+                        warn!("client disconnected; cancelling download of {cache_path}");
+
+                        if let Some(w) = writer.take() {
+                            w.abort().await;
+                            Stats::add(&stats.mirrors_aborted, 1);
+                        }
+
+                        return Ok(());
+                    }
+                }
+
+                // Finalize the mirror if it has not been aborted, provided the symbol looks legitimate.
+                if let Some(w) = writer {
+                    if signature.is_some_and(|s| s != prefix) {
+                        warn!("{cache_path} does not have the expected signature; refusing to mirror it");
+                        w.abort().await;
+                        Stats::add(&stats.mirrors_aborted, 1);
+                    } else if expected_len.is_some_and(|l| l != len) {
+                        warn!(
+                            "{cache_path} was {len} bytes, but upstream declared {} bytes; refusing to mirror it",
+                            expected_len.unwrap_or_default()
+                        );
+                        w.abort().await;
+                        Stats::add(&stats.mirrors_aborted, 1);
+                    } else if let Err(e) = w.finish(&url, digest.map(encode_digest)).await {
+                        error!("{:?}", e.context("failed to mirror symbol"));
+                        Stats::add(&stats.mirrors_aborted, 1);
+                    } else {
+                        Stats::add(&stats.mirrors_completed, 1);
+
+                        if let Some(index) = &index {
+                            index.insert(cache_path);
+                        }
+                    }
+                }
+
+                Ok::<(), anyhow::Error>(())
+            });
+
+            Box::pin(ReceiverStream::new(rx))
+        } else {
+            // Note when a response bypasses a configured cache.
+            if config.cache.is_some() {
+                metrics::counter!("cache_skipped_total").increment(1);
+            }
+
+            upstream
+        };
+
+        // N.B: The fetch permit (if any) is held until the client has received the symbol, as well
+        // as by the mirror.
+        let permit = fetch_permit.clone();
+        let stream = stream.inspect(move |_| {
+            let _ = &permit;
+        });
+
+        // Serve only the requested range, once the full symbol has been received (and mirrored).
+        if let Some(range) = range {
+            let body = match stream.try_collect::<Vec<_>>().await {
+                Ok(chunks) => chunks.concat(),
+                Err(e) => {
+                    // Log the failure and move on to the next server.
+                    let e = anyhow::Error::new(e)
+                        .context(format!("failed to read response body from {url}"));
+                    error!("{e:?}");
+                    stats.record_error(idx, &e);
+
+                    unreachable += 1;
+                    continue;
+                }
+            };
+
+            Stats::add(&stats.upstream_hits, 1);
+            return Ok(range_response(response_builder, body, range)?);
+        }
+
+        // Stream out the response from the upstream server as we receive it.
+        Stats::add(&stats.upstream_hits, 1);
+        return Ok(response_builder
+            .body(Body::from_stream(stream))
+            .context("failed to build response body")?);
+    }
+
+    // Fall back to the local store, if it has not already been consulted.
+    if !config.local_first {
+        if let Some(response) =
+            local_lookup(&state, local.as_ref(), &symbol_path, &name2, accept_zstd).await?
+        {
+            return Ok(response);
+        }
+    }
+
+    if unreachable != 0 {
+        // Some upstream servers could not be reached. Make one last attempt to serve the symbol
+        // from the cache in case it was mirrored in the meantime (e.g. by another request or
+        // another instance sharing the same cache).
+        if let Some(cache) = cache {
+            if let Some(hit) = cache_lookup(&state, cache, &cache_path, accept_zstd).await? {
+                Stats::add(&stats.cache_hits, 1);
+                return Ok(cache_response(
+                    hit,
+                    content_type(&name2, &config.content_types),
+                )?);
+            }
+        }
+
+        Stats::add(&stats.misses, 1);
+
+        // If every upstream server was unreachable, we can't say for certain that the symbol
+        // does not exist.
+        if unreachable == config.servers.len() {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::empty())
+                .context("failed to build response body")?);
+        }
+    } else {
+        Stats::add(&stats.misses, 1);
+    }
+
+    Ok(not_found_response(config)?)
+}
+
+/// The URL of a symbol on `server`.
+fn symbol_url(server: &ConfigServer, name1: &str, hash: &str, name2: &str) -> anyhow::Result<Url> {
+    let path = expand_path_template(
+        server
+            .path_template
+            .as_deref()
+            .unwrap_or(DEFAULT_PATH_TEMPLATE),
+        name1,
+        hash,
+        name2,
+    );
+
+    let url = server
+        .url
+        .join(&path)
+        .context("failed to build request url")?;
+
+    confine_url(&server.url, url)
+}
+
+/// Ensure that `url`, built by joining a client-provided path onto `base`, has not escaped it.
+///
+/// URLs on another origin (or outside of the base path) would receive the server's credentials,
+/// so they are refused.
+fn confine_url(base: &Url, url: Url) -> anyhow::Result<Url> {
+    if url.origin() != base.origin() || !url.path().starts_with(base.path()) {
+        anyhow::bail!("refusing to request {url}, as it is outside of {base}");
+    }
+
+    Ok(url)
+}
+
+/// The cache key of a symbol.
+fn cache_key(config: &AppConfig, name1: &str, hash: &str, name2: &str) -> String {
+    match config.cache_key_case {
+        ConfigKeyCase::Preserve => format!("{name1}/{hash}/{name2}"),
+        ConfigKeyCase::Lower => format!("{}/{hash}/{}", name1.to_lowercase(), name2.to_lowercase()),
+    }
+}
+
+/// Determine whether `server` may be sent `HEAD` requests for symbols, which is not the case for
+/// servers expecting a request body.
+fn head_supported(server: &ConfigServer) -> bool {
+    server.method() == reqwest::Method::GET && server.body_template.is_none()
+}
+
+/// Send a `HEAD` request for the symbol at `url` to the server at `idx`.
+async fn head_request(
+    state: &AppState,
+    idx: usize,
+    url: &Url,
+) -> anyhow::Result<reqwest::Response> {
+    let server = &state.config.servers[idx];
+    let client = &state.server_clients[idx];
+
+    let mut request = upstream_request(client, server, state.token.as_ref(), url, None)
+        .await?
+        .build()?;
+    *request.method_mut() = reqwest::Method::HEAD;
+
+    let request = reqwest::RequestBuilder::from_parts(client.clone(), request);
+    send_request(&state.config, server, request).await
+}
+
+/// Ask the server at `idx` whether it has a symbol with a `HEAD` request.
+///
+/// Returns `None` if that could not be determined, e.g. because the server does not support
+/// `HEAD` requests or failed to respond.
+async fn probe(state: &AppState, idx: usize, name1: &str, hash: &str, name2: &str) -> Option<bool> {
+    let config = &state.config;
+    let server = &config.servers[idx];
+    if !head_supported(server) {
+        return None;
+    }
+
+    let url = symbol_url(server, name1, hash, name2).ok()?;
+    if !host_allowed(config.allowed_upstream_hosts.as_deref(), &url) {
+        return None;
+    }
+
+    match head_request(state, idx, &url).await {
+        Ok(res) if res.status().is_success() => Some(true),
+        Ok(res) if matches!(res.status(), StatusCode::NOT_FOUND | StatusCode::GONE) => Some(false),
+        Ok(res) => {
+            debug!("probe of {url} was inconclusive: {}", res.status());
+            None
+        }
+        Err(e) => {
+            debug!("{:?}", e.context(format!("failed to probe {url}")));
+            None
+        }
+    }
+}
+
+/// Reorder `order` by probing every server concurrently, such that the first server found to
+/// have the symbol is consulted first and servers known not to have it are skipped.
+///
+/// Preferred servers are probed first (in order), and the rest are only raced if none of them
+/// have the symbol. Outstanding probes are cancelled as soon as one succeeds.
+async fn race_servers(
+    state: &AppState,
+    order: Vec<usize>,
+    name1: &str,
+    hash: &str,
+    name2: &str,
+) -> Vec<usize> {
+    let servers = &state.config.servers;
+    let mut misses = std::collections::HashSet::new();
+    let mut winner = None;
+
+    for &idx in order.iter().filter(|&&i| servers[i].preferred) {
+        match probe(state, idx, name1, hash, name2).await {
+            Some(true) => {
+                winner = Some(idx);
+                break;
+            }
+            Some(false) => {
+                misses.insert(idx);
+            }
+            None => {}
+        }
+    }
+
+    if winner.is_none() {
+        let mut probes = order
+            .iter()
+            .filter(|&&i| !servers[i].preferred)
+            .map(|&idx| async move { (idx, probe(state, idx, name1, hash, name2).await) })
+            .collect::<futures::stream::FuturesUnordered<_>>();
+
+        while let Some((idx, found)) = probes.next().await {
+            match found {
+                Some(true) => {
+                    winner = Some(idx);
+                    break;
+                }
+                Some(false) => {
+                    misses.insert(idx);
+                }
+                None => {}
+            }
+        }
+    }
+
+    winner
+        .into_iter()
+        .chain(
+            order
+                .into_iter()
+                .filter(|&i| Some(i) != winner && !misses.contains(&i)),
+        )
+        .collect()
+}
+
+/// Build a request for the symbol at `url` on `server`.
+async fn upstream_request(
+    client: &reqwest::Client,
+    server: &ConfigServer,
+    token: &dyn TokenCredential,
+    url: &Url,
+    body: Option<&str>,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    let req_builder = client.request(server.method(), url.clone());
+
+    // Some servers expect the symbol to be described in the request body instead of the path.
+    let req_builder = match body {
+        Some(body) => req_builder
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_string()),
+        None => req_builder,
+    };
+
+    // Target a specific backend behind a shared ingress, if requested.
+    let req_builder = match &server.host_header {
+        Some(host) => req_builder.header(header::HOST, host),
+        None => req_builder,
+    };
+
+    // N.B: Credentials take precedence over static headers: a bearer token (if `auth` is set) over
+    // basic authentication, over any `Authorization` header. Only one of them is ever sent.
+    let authenticated = server.auth.is_some() || server.basic_auth.is_some();
+
+    let mut req_builder = req_builder;
+    for (name, value) in &server.headers {
+        if authenticated && name.eq_ignore_ascii_case(header::AUTHORIZATION.as_str()) {
+            continue;
+        }
+
+        req_builder = req_builder.header(name, value.resolve()?);
+    }
+
+    if let (None, Some(basic)) = (&server.auth, &server.basic_auth) {
+        req_builder = req_builder.basic_auth(&basic.username, Some(basic.password.resolve()?));
+    }
+
+    // If there is a scope attached to this server, attempt to authenticate.
+    Ok(if let Some(auth) = &server.auth {
+        req_builder.bearer_auth(
+            token
+                .get_token(&[&auth.scope])
+                .await
+                .context("failed to get token")?
+                .token
+                .secret(),
+        )
+    } else {
+        req_builder
+    })
+}
+
+/// Determine the headers forwarded to the client from an upstream response for the symbol
+/// `name2` at `url`.
+fn forwarded_headers(
+    config: &AppConfig,
+    server: &ConfigServer,
+    url: &Url,
+    name2: &str,
+    upstream: &HeaderMap,
+) -> HeaderMap {
+    let mut headers = upstream.clone();
+
+    strip_hop_by_hop(&mut headers);
+    sanitize_headers(&mut headers, config.duplicate_headers, url);
+
+    // Drop any headers the operator does not want leaking from upstream.
+    if let Some(filter) = &config.response_headers {
+        let denied = headers
+            .keys()
+            .filter(|name| !filter.forwarded(name.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in denied {
+            headers.remove(name);
+        }
+    }
+
+    // Insert an additional header describing where this symbol originated from.
+    headers.insert(UPSTREAM_SOURCE, HeaderValue::from_static("server"));
+    headers.insert(X_CACHE, HeaderValue::from_static("MISS"));
+    headers.insert(
+        UPSTREAM_SERVER,
+        HeaderValue::from_str(server.url.as_str()).unwrap(),
+    );
+
+    // Correct the content type reported by upstream, if configured to.
+    if config.override_upstream_content_type {
+        if let Some(content_type) = known_content_type(name2, &config.content_types)
+            .and_then(|t| HeaderValue::from_str(t).ok())
+        {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+    }
+
+    headers
+}
+
+/// Remove the headers describing the connection to upstream rather than the symbol itself, which
+/// would otherwise corrupt the framing of our own response.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    const HOP_BY_HOP: &[&str] = &[
+        "connection",
+        "keep-alive",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "proxy-connection",
+        "te",
+        "trailer",
+        "transfer-encoding",
+        "upgrade",
+    ];
+
+    // N.B: `Connection` may name additional headers that only apply to this hop.
+    let named = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| header::HeaderName::from_str(name.trim()).ok())
+        .collect::<Vec<_>>();
+
+    for name in named {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Collapse repeated occurrences of headers that may only appear once, which would otherwise
+/// produce a response that clients refuse to parse.
+fn sanitize_headers(headers: &mut HeaderMap, keep: ConfigDuplicateHeaders, url: &Url) {
+    const SINGLE_VALUED: &[header::HeaderName] = &[
+        header::CONTENT_LENGTH,
+        header::CONTENT_TYPE,
+        header::CONTENT_ENCODING,
+        header::CONTENT_RANGE,
+        header::ETAG,
+        header::LAST_MODIFIED,
+        header::LOCATION,
+    ];
+
+    for name in SINGLE_VALUED {
+        let values = headers.get_all(name).iter().cloned().collect::<Vec<_>>();
+        if values.len() < 2 {
+            continue;
+        }
+
+        if values.iter().any(|v| v != &values[0]) {
+            warn!("{url} sent conflicting `{name}` headers: {values:?}");
+        }
+
+        let value = match keep {
+            ConfigDuplicateHeaders::First => values.first(),
+            ConfigDuplicateHeaders::Last => values.last(),
+        };
+
+        if let Some(value) = value.cloned() {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Look up a symbol in the cache, consulting its in-memory index (if any) first.
+async fn cache_lookup(
+    state: &AppState,
+    cache: &ConfigCache,
+    path: &str,
+    accept_zstd: bool,
+) -> anyhow::Result<Option<cache::CacheHit>> {
+    if state.config.disable_cache_reads {
+        return Ok(None);
+    }
+
+    let index = state.cache_index();
+
+    // Symbols absent from the index are not cached, so there is no need to check the filesystem.
+    if index.is_some_and(|i| !i.contains(path)) {
+        return Ok(None);
+    }
+
+    let hit = cache::lookup(cache, state.token.clone(), path, accept_zstd).await?;
+    if hit.is_none() {
+        // The entry was removed behind our back, so stop believing it is cached.
+        if let Some(index) = index {
+            index.remove(path);
+        }
+    }
+
+    Ok(hit)
+}
+
+/// Determine whether the symbol at `path`, of length `len` (if known), may be mirrored to `cache`
+/// however it was received. `hot` is whether it has been requested often enough to be cached.
+fn mirror_allowed(
+    state: &AppState,
+    cache: &ConfigCache,
+    path: &str,
+    hot: bool,
+    len: Option<u64>,
+) -> bool {
+    let config = &state.config;
+    let cacheable_size = match len {
+        Some(len) => {
+            config.min_cache_file_size.map_or(true, |min| len >= min)
+                && config.max_cache_file_size.map_or(true, |max| len <= max)
+        }
+        None => config.cache_unknown_size,
+    };
+
+    // Writes may have been disabled by an administrator, e.g. to stop a bad upstream from
+    // polluting the cache.
+    state.cache_writes.load(Ordering::Relaxed)
+        // Symbols that have not yet been requested often enough are not worth caching.
+        && hot
+        // Neither are symbols outside of the configured size range.
+        && cacheable_size
+        // Nor are symbols that would leave too little room on the cache's volume.
+        && cache::has_room(cache, path, len)
+}
+
+/// Acquire a permit to mirror the symbol at `path`, if mirroring is limited.
+///
+/// Symbols are served without being mirrored rather than waiting for a permit to become available.
+fn mirror_permit(state: &AppState, path: &str) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    let Some(permits) = &state.mirror_permits else {
+        return Ok(None);
+    };
+
+    match permits.clone().try_acquire_owned() {
+        Ok(permit) => Ok(Some(permit)),
+        Err(_) => {
+            warn!("mirror limit reached; serving {path} without mirroring");
+            Err(())
+        }
+    }
+}
+
+/// Mirror a symbol that has already been received in full from `url` to `cache` (if any) at
+/// `path`, subject to the same checks as symbols mirrored as they are streamed (see
+/// [`mirror_allowed`]).
+///
+/// Failures are only logged, as the symbol can still be served.
+async fn mirror_complete(
+    state: &AppState,
+    cache: Option<&ConfigCache>,
+    path: &str,
+    hot: bool,
+    url: &Url,
+    body: &Bytes,
+    sha256: Option<String>,
+) {
+    let Some(cache) = cache else {
+        return;
+    };
+
+    if !mirror_allowed(state, cache, path, hot, Some(body.len() as u64)) {
+        return;
+    }
+
+    let Ok(_permit) = mirror_permit(state, path) else {
+        return;
+    };
+
+    let result = async {
+        let mut writer = cache::CacheWriter::create(cache, state.token.clone(), path).await?;
+        writer.write(body.clone()).await?;
+        writer.finish(url, sha256).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            Stats::add(&state.stats.mirrors_completed, 1);
+
+            if let Some(index) = state.cache_index() {
+                index.insert(path.to_string());
+            }
+        }
+        Err(e) => {
+            warn!("{:?}", e.context(format!("failed to mirror {path}")));
+            Stats::add(&state.stats.mirrors_aborted, 1);
+        }
+    }
+}
+
+/// Attempt to serve a symbol from the local store, if one is configured.
+async fn local_lookup(
+    state: &AppState,
+    local: Option<&ConfigCache>,
+    path: &str,
+    name2: &str,
+    accept_zstd: bool,
+) -> anyhow::Result<Option<Response>> {
+    let Some(local) = local else {
+        return Ok(None);
+    };
+
+    let Some(hit) = cache::lookup(local, state.token.clone(), path, accept_zstd).await? else {
+        return Ok(None);
+    };
+
+    Stats::add(&state.stats.local_hits, 1);
+
+    let mut response = cache_response(hit, content_type(name2, &state.config.content_types))?;
+    response
+        .headers_mut()
+        .insert(UPSTREAM_SOURCE, HeaderValue::from_static("local"));
+
+    Ok(Some(response))
+}
+
+/// Build the response returned when a symbol cannot be found.
+fn not_found_response(config: &AppConfig) -> anyhow::Result<Response> {
+    let not_found = config.not_found.as_ref();
+
+    let status = match not_found.and_then(|n| n.status) {
+        Some(status) => StatusCode::from_u16(status).context("invalid not found status")?,
+        None => StatusCode::NOT_FOUND,
+    };
+    let body = match not_found.and_then(|n| n.body.clone()) {
+        Some(body) => Body::from(body),
+        None => Body::empty(),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(body)
+        .context("failed to build response body")
+}
+
+/// Compare two secrets in constant time.
+fn secrets_match(a: &[u8], b: &[u8]) -> bool {
+    // N.B: Hash both inputs first so that the comparison below does not leak their lengths.
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests that do not present the configured admin token.
+async fn require_admin_token(
+    State(config): State<AppConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match (presented, &config.admin_token) {
+        (Some(presented), Some(expected))
+            if secrets_match(presented.as_bytes(), expected.expose().as_bytes()) =>
+        {
+            next.run(request).await
+        }
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+/// Middleware rejecting requests that do not present one of the configured API keys.
+async fn require_api_key(
+    State(config): State<AppConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    // N.B: Every key is compared, so that the time taken does not reveal which one matched.
+    let valid = presented.is_some_and(|presented| {
+        config.api_keys.iter().fold(false, |valid, key| {
+            valid | secrets_match(presented.as_bytes(), key.expose().as_bytes())
+        })
+    });
+
+    if valid {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// The routes serving symbols, which require an API key if any have been configured.
+fn symbol_routes(state: &AppState) -> Router<AppState> {
+    with_api_keys(
+        state,
+        Router::new().route("/:name1/:hash/:name2", get(symbol).head(symbol_head)),
+    )
+}
+
+/// Require an API key for the routes of `router`, if any have been configured.
+fn with_api_keys(state: &AppState, router: Router<AppState>) -> Router<AppState> {
+    if state.config.api_keys.is_empty() {
+        return router;
+    }
+
+    router.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        require_api_key,
+    ))
+}
+
+/// Endpoint used by Azure to query this application's health status.
+async fn health(
+    State(config): State<AppConfig>,
+    State(client): State<reqwest::Client>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    // Check to see if the request originates from Azure.
+    // https://learn.microsoft.com/en-us/azure/app-service/monitor-instances-health-check?tabs=dotnet#authentication-and-security
+    if let Some(key) = std::env::var_os("WEBSITE_AUTH_ENCRYPTION_KEY") {
+        let f = || {
+            // FIXME: According to the documentation, this header is only provided on Windows instances. Sigh.
+            let auth_token = headers
+                .get(INTERNAL_AUTH_TOKEN)
+                .context("missing internal auth token")?;
+
+            let hash = {
+                let mut sha = Sha256::new();
+                sha.update(key.as_encoded_bytes());
+                let hash = sha.finalize();
+
+                base64::prelude::BASE64_STANDARD.encode(hash.as_slice())
+            };
+
+            if hash.as_bytes() == auth_token.as_bytes() {
+                Ok(())
+            } else {
+                anyhow::bail!("invalid authentication hash");
+            }
+        };
+
+        match (f)() {
+            // Continue on.
+            Ok(_) => (),
+            // If validation fails for any reason, just return an opaque 401 response.
+            Err(_e) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Body::empty())
+                    .context("failed to build response body")?)
             }
         }
     }
 
     // Run through every configured server and ensure they are reachable.
-    for server in &config.servers {
+    for server in config.all_servers() {
         // Send a request to the root of the symbol server. Ignore the response
         // since we are only interested in seeing if the symbol server responds.
-        let _req = reqwest::get(server.url.clone())
+        let _req = send_request(&config, server, client.get(server.url.clone()))
             .await
             .with_context(|| format!("symbol server \"{}\" is unreachable", server.url))?;
     }
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::empty())
-        .context("failed to build response body")?)
-}
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::empty())
+        .context("failed to build response body")?)
+}
+
+/// Cheap readiness endpoint for load balancers and orchestrators, which never contacts an
+/// upstream server.
+async fn healthz(
+    State(config): State<AppConfig>,
+    State(token): State<Arc<dyn TokenCredential>>,
+) -> Response {
+    if config.healthz_check_auth {
+        // N.B: Tokens are cached by the credential, so this is only slow when one is refreshed.
+        if let Some(auth) = config.all_servers().find_map(|s| s.auth.as_ref()) {
+            if let Err(e) = token.get_token(&[&auth.scope]).await {
+                warn!(
+                    "{:?}",
+                    anyhow::Error::new(e).context("readiness check failed to get token")
+                );
+                return (StatusCode::SERVICE_UNAVAILABLE, "unable to authenticate").into_response();
+            }
+        }
+    }
+
+    (StatusCode::OK, "ok").into_response()
+}
+
+/// Repeatedly attempt to acquire a token for `scope`, backing off between attempts, until one
+/// is successfully acquired.
+async fn acquire_token_with_retry(token: &dyn TokenCredential, scope: &str, url: &Url) {
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        match token.get_token(&[scope]).await {
+            Ok(_) => return,
+            Err(e) => {
+                warn!(
+                    "{:?}",
+                    anyhow::Error::new(e).context(format!(
+                        "failed to get token for {url}, retrying in {}s",
+                        delay.as_secs()
+                    ))
+                );
+
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+/// Register descriptions for the metrics emitted by this application.
+fn describe_metrics() {
+    metrics::describe_counter!(
+        "bytes_served_total",
+        metrics::Unit::Bytes,
+        "Total number of symbol bytes served to clients"
+    );
+    metrics::describe_counter!(
+        "bytes_cached_total",
+        metrics::Unit::Bytes,
+        "Total number of symbol bytes written to the cache backend"
+    );
+    metrics::describe_histogram!(
+        "upstream_time_to_first_byte_seconds",
+        metrics::Unit::Seconds,
+        "Time from receipt of a request to the first byte received from an upstream server"
+    );
+    metrics::describe_histogram!(
+        "upstream_transfer_seconds",
+        metrics::Unit::Seconds,
+        "Time from the first to the last byte received from an upstream server"
+    );
+    metrics::describe_histogram!(
+        "upstream_response_seconds",
+        metrics::Unit::Seconds,
+        "Time from sending a request to an upstream server to receiving its response headers"
+    );
+    metrics::describe_counter!(
+        "upstream_requests_total",
+        "Total number of requests to each upstream server, by whether they succeeded"
+    );
+    metrics::describe_counter!(
+        "requests_total",
+        "Total number of symbol requests, by response status"
+    );
+    metrics::describe_counter!(
+        "cache_hits_total",
+        "Total number of symbols served from the cache"
+    );
+    metrics::describe_counter!(
+        "cache_misses_total",
+        "Total number of symbols not found in the cache"
+    );
+    metrics::describe_counter!(
+        "symbols_spilled_total",
+        "Total number of symbols spilled to disk because a client fell behind"
+    );
+    metrics::describe_gauge!(
+        "negative_cache_entries",
+        "Number of symbols currently remembered as missing from an upstream server"
+    );
+    metrics::describe_gauge!(
+        "downloads_in_flight",
+        "Number of symbol downloads from upstream servers currently in progress"
+    );
+    metrics::describe_counter!(
+        "requests_coalesced_total",
+        "Total number of requests coalesced onto an existing download"
+    );
+    metrics::describe_counter!(
+        "cache_skipped_total",
+        "Total number of upstream responses that bypassed a configured cache"
+    );
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Set up trace logging to console. The verbosity flag sets the default level, which may be
+    // refined by `RUST_LOG` or (taking precedence) `--log-filter`.
+    let lvl = match args.verbosity.log_level_filter() {
+        LevelFilter::Off => tracing_subscriber::filter::LevelFilter::OFF,
+        LevelFilter::Error => tracing_subscriber::filter::LevelFilter::ERROR,
+        LevelFilter::Warn => tracing_subscriber::filter::LevelFilter::WARN,
+        LevelFilter::Info => tracing_subscriber::filter::LevelFilter::INFO,
+        LevelFilter::Debug => tracing_subscriber::filter::LevelFilter::DEBUG,
+        LevelFilter::Trace => tracing_subscriber::filter::LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::builder().with_default_directive(lvl.into());
+    let filter = match &args.log_filter {
+        Some(directives) => filter
+            .parse(directives)
+            .context("failed to parse log filter")?,
+        None => filter.from_env_lossy(),
+    };
+
+    let config = load_config(&args)?;
+
+    // N.B: JSON events include the fields of the spans they occur in (e.g. the method and URI of
+    // the request span created by `TraceLayer`).
+    let json = args.log_format == LogFormat::Json;
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(tracing_subscriber::fmt::layer))
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(true)
+        }));
+
+    // Export spans to an OpenTelemetry collector, if configured.
+    #[cfg(feature = "otel")]
+    let registry = registry.with(config.otel.as_ref().map(otel::layer).transpose()?);
+    #[cfg(not(feature = "otel"))]
+    if config.otel.is_some() {
+        anyhow::bail!("`otel` is configured, but this build does not include the `otel` feature.");
+    }
+    #[cfg(not(feature = "verify-identity"))]
+    if config.verify_identity {
+        anyhow::bail!(
+            "`verify_identity` is set, but this build does not include the `verify-identity` feature."
+        );
+    }
+
+    registry.init();
+
+    // N.B: The recorder must be installed before metrics are described, or the descriptions are lost.
+    let metrics = config
+        .metrics
+        .then(|| PrometheusBuilder::new().install_recorder())
+        .transpose()
+        .context("failed to install metrics recorder")?;
+
+    // N.B: Dump the configuration as it was actually understood (including defaults), to make
+    // configuration mistakes easy to spot. Secrets are redacted.
+    info!("effective configuration: {config:#?}");
+
+    describe_metrics();
+
+    let result = run(args.command, config, metrics).await;
+
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+
+    result
+}
+
+/// Run the requested command, or serve requests if none was given.
+async fn run(
+    command: Option<Command>,
+    config: AppConfig,
+    metrics: Option<PrometheusHandle>,
+) -> anyhow::Result<()> {
+    match command {
+        Some(Command::Fetch {
+            name1,
+            hash,
+            name2,
+            output,
+        }) => fetch(build_state(config).await?, name1, hash, name2, output).await,
+        Some(Command::CacheExport { output }) => cache_export(config, output).await,
+        Some(Command::CacheImport { manifest }) => cache_import(config, manifest).await,
+        Some(Command::Validate) => validate(config).await,
+        None => serve(build_state(config).await?, metrics).await,
+    }
+}
+
+/// Ensure that files can be written to `dir`, creating it if necessary.
+fn check_writable(dir: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let probe = dir.join(format!(".symproxy-probe-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+/// Check, without modifying anything, that `dir` (or its nearest existing ancestor, which it would
+/// be created under) is a directory that is not read-only.
+fn check_not_readonly(dir: &std::path::Path) -> std::io::Result<()> {
+    let existing = dir
+        .ancestors()
+        .find(|d| d.exists())
+        .unwrap_or(std::path::Path::new("."));
+    let metadata = std::fs::metadata(existing)?;
+
+    if !metadata.is_dir() {
+        return Err(std::io::Error::other(format!(
+            "{} is not a directory",
+            existing.display()
+        )));
+    }
+
+    if metadata.permissions().readonly() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is read-only", existing.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Read, parse, and validate the user-provided configuration.
+fn load_config(args: &Args) -> anyhow::Result<AppConfig> {
+    // Read and parse the user-provided configuration.
+    let toml = if let Some(inline) = &args.config_inline {
+        figment::providers::Toml::string(inline)
+    } else if args.config.as_os_str() == "-" {
+        let mut s = String::new();
+        std::io::stdin()
+            .read_to_string(&mut s)
+            .context("failed to read configuration from stdin")?;
+
+        figment::providers::Toml::string(&s)
+    } else {
+        figment::providers::Toml::file(&args.config)
+    };
+
+    let mut config: AppConfig = Figment::new()
+        .merge(toml)
+        .merge(figment::providers::Env::prefixed("SYMPROXY_"))
+        .extract()
+        .context("failed to load configuration")?;
+
+    if args.disable_cache_writes {
+        config.disable_cache_writes = true;
+    }
+
+    // N.B: The `validate` subcommand must not leave anything behind, so it does not probe
+    // directories by writing to them.
+    config.validate(!matches!(args.command, Some(Command::Validate)))?;
+    Ok(config)
+}
+
+impl AppConfig {
+    /// Check the configuration for mistakes, reporting every problem found at once.
+    ///
+    /// Directories are probed by writing to them (and created, if missing) only if `probe` is set.
+    fn validate(&self, probe: bool) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        let check_dir = if probe {
+            check_writable
+        } else {
+            check_not_readonly
+        };
+
+        if self.servers.is_empty() && self.realms.is_empty() && self.local_store.is_none() {
+            problems.push(
+                "You must provide at least one upstream server in your configuration file."
+                    .to_string(),
+            );
+        }
+
+        // Surface an unusable temporary directory now, rather than on the first slow client.
+        if self.spill_to_disk || self.temp_dir.is_some() {
+            let dir = self.temp_dir();
+            if let Err(e) = check_dir(&dir) {
+                problems.push(format!(
+                    "The temporary directory {} is not writable ({e}); check `temp_dir`.",
+                    dir.display()
+                ));
+            }
+        }
+
+        // Likewise, surface an unusable cache directory now rather than on the first cache miss.
+        if !self.disable_cache_writes {
+            let caches = self
+                .cache
+                .iter()
+                .chain(self.realms.values().flat_map(|r| &r.cache));
+            for cache in caches {
+                let dir = match cache {
+                    ConfigCache::Fs(cache) => &cache.path,
+                    ConfigCache::Azure(ConfigAzureCache {
+                        local_cache_dir: Some(dir),
+                        ..
+                    }) => dir,
+                    _ => continue,
+                };
+
+                if let Err(e) = check_dir(dir) {
+                    problems.push(format!(
+                        "The cache directory {} is not writable ({e}).",
+                        dir.display()
+                    ));
+                }
+            }
+        }
+
+        if let Some(base) = &self.base_path {
+            if !base.starts_with('/') || base.len() < 2 || base.ends_with('/') {
+                problems.push("`base_path` must begin with, but not end with, a `/`.".to_string());
+            }
+        }
+
+        let mut prefixes = std::collections::HashSet::new();
+        for (name, realm) in &self.realms {
+            if realm.servers.is_empty() {
+                problems.push(format!(
+                    "You must provide at least one upstream server for realm \"{name}\"."
+                ));
+            }
+
+            if !realm.prefix.starts_with('/')
+                || realm.prefix.len() < 2
+                || realm.prefix.ends_with('/')
+            {
+                problems.push(format!(
+                    "The prefix for realm \"{name}\" must begin with, but not end with, a `/`."
+                ));
+            }
+
+            if !prefixes.insert(&realm.prefix) {
+                problems.push(format!(
+                    "The prefix for realm \"{name}\" is used by another realm."
+                ));
+            }
+        }
+
+        if let Some(sources) = &self.sources {
+            if sources.servers.is_empty() {
+                problems.push(
+                    "You must provide at least one upstream server for `sources`.".to_string(),
+                );
+            }
+
+            let prefix = sources.prefix.as_deref().unwrap_or(DEFAULT_SOURCE_PREFIX);
+            if !prefix.starts_with('/') || prefix.len() < 2 || prefix.ends_with('/') {
+                problems
+                    .push("`sources.prefix` must begin with, but not end with, a `/`.".to_string());
+            }
+
+            if self.realms.values().any(|r| r.prefix == prefix) {
+                problems.push("`sources.prefix` is used by a realm.".to_string());
+            }
+        }
+
+        if let Some(server) = self
+            .all_servers()
+            .find(|s| !host_allowed(self.allowed_upstream_hosts.as_deref(), &s.url))
+        {
+            problems.push(format!(
+                "The upstream server \"{}\" is not in `allowed_upstream_hosts`.",
+                server.url
+            ));
+        }
+
+        if cfg!(not(unix)) && self.all_servers().any(|s| s.unix_socket.is_some()) {
+            problems.push("`unix_socket` is not supported on this platform.".to_string());
+        }
+
+        for server in self.all_servers() {
+            if let Some(method) = &server.method {
+                if reqwest::Method::from_str(&method.to_uppercase()).is_err() {
+                    problems.push(format!(
+                        "Invalid `method` \"{method}\" for \"{}\".",
+                        server.url
+                    ));
+                }
+            }
+
+            for (name, value) in &server.headers {
+                if header::HeaderName::from_str(name).is_err() {
+                    problems.push(format!(
+                        "Invalid header name \"{name}\" for \"{}\".",
+                        server.url
+                    ));
+                }
+
+                if let Err(e) = value.resolve() {
+                    problems.push(format!(
+                        "Invalid value for header \"{name}\" for \"{}\": {e:#}.",
+                        server.url
+                    ));
+                }
+            }
+
+            if server
+                .auth
+                .as_ref()
+                .is_some_and(|a| a.scope.trim().is_empty())
+            {
+                problems.push(format!(
+                    "`auth.scope` for \"{}\" must not be empty.",
+                    server.url
+                ));
+            }
+
+            if server.weight == Some(0) {
+                problems.push(format!(
+                    "`weight` for \"{}\" must be greater than zero.",
+                    server.url
+                ));
+            }
+
+            for key in server.status_actions.keys() {
+                let valid = match key.as_bytes() {
+                    [c, b'x', b'x'] => (b'1'..=b'5').contains(c),
+                    _ => key.parse::<StatusCode>().is_ok(),
+                };
+
+                if !valid {
+                    problems.push(format!(
+                        "Invalid status code \"{key}\" in `status_actions` for \"{}\".",
+                        server.url
+                    ));
+                }
+            }
+        }
+
+        let server_limits = self.all_servers().map(|s| s.bandwidth_limit);
+        if std::iter::once(self.bandwidth_limit)
+            .chain(server_limits)
+            .any(|l| l == Some(0))
+        {
+            problems.push("`bandwidth_limit` must be greater than zero.".to_string());
+        }
+
+        if self.max_concurrent_mirrors == Some(0) {
+            problems.push(
+                "`max_concurrent_mirrors` must be greater than zero; use `disable_cache_writes` to stop mirroring."
+                    .to_string(),
+            );
+        }
+
+        let caches = self
+            .cache
+            .iter()
+            .chain(self.realms.values().flat_map(|r| &r.cache));
+        for cache in caches {
+            match cache {
+                ConfigCache::Azure(cache) => {
+                    // N.B: Azure requires account names to be 3 to 24 lowercase letters and digits.
+                    let account = &cache.storage_account;
+                    if !(3..=24).contains(&account.len())
+                        || !account
+                            .bytes()
+                            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+                    {
+                        problems.push(format!(
+                            "`cache.storage_account` \"{account}\" is not a valid storage account name."
+                        ));
+                    }
+
+                    if cache.storage_container.is_empty() {
+                        problems.push("`cache.storage_container` must not be empty.".to_string());
+                    }
+
+                    // N.B: Azure limits blocks to 4000 MiB.
+                    if let Some(size) = cache.block_size {
+                        if size == 0 || size as u64 > 4000 * 1024 * 1024 {
+                            problems.push(
+                                "`cache.block_size` must be between 1 byte and 4000 MiB."
+                                    .to_string(),
+                            );
+                        }
+                    }
+                }
+                ConfigCache::Fs(cache) => {
+                    if cache.path.as_os_str().is_empty() {
+                        problems.push("`cache.path` must not be empty.".to_string());
+                    }
+                }
+                #[cfg(feature = "memory-cache")]
+                ConfigCache::Memory(_) => {}
+            }
+        }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+        if let Some(rate) = self.otel.as_ref().and_then(|o| o.sample_rate) {
+            if !(0.0..=1.0).contains(&rate) {
+                problems.push(format!(
+                    "`otel.sample_rate` must be between 0 and 1, but {rate} was given."
+                ));
+            }
+        }
 
-    // Set up trace logging to console and account for the user-provided verbosity flag.
-    if args.verbosity.log_level_filter() != LevelFilter::Off {
-        let lvl = match args.verbosity.log_level_filter() {
-            LevelFilter::Off => tracing::Level::INFO,
-            LevelFilter::Error => tracing::Level::ERROR,
-            LevelFilter::Warn => tracing::Level::WARN,
-            LevelFilter::Info => tracing::Level::INFO,
-            LevelFilter::Debug => tracing::Level::DEBUG,
-            LevelFilter::Trace => tracing::Level::TRACE,
-        };
-        tracing_subscriber::fmt().with_max_level(lvl).init();
-    }
+        if let Some(status) = self.not_found.as_ref().and_then(|n| n.status) {
+            if !(400..500).contains(&status) {
+                problems.push(format!(
+                    "`not_found.status` must be a 4xx status code, but {status} was given."
+                ));
+            }
+        }
 
-    // Read and parse the user-provided configuration.
-    let mut config: AppConfig = Figment::new()
-        .merge(figment::providers::Toml::file(args.config))
-        .merge(figment::providers::Env::prefixed("SYMPROXY_"))
-        .extract()
-        .context("failed to load configuration")?;
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "The configuration is invalid:\n{}",
+                problems
+                    .iter()
+                    .map(|p| format!("- {p}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
 
-    // Validation.
-    if config.servers.is_empty() {
-        anyhow::bail!("You must provide at least one upstream server in your configuration file.");
+        Ok(())
     }
+}
 
-    // Authenticate.
-    //
+/// Create the credential used to authenticate to upstream servers and the cache.
+fn create_credential(config: &AppConfig) -> anyhow::Result<Arc<dyn TokenCredential>> {
     // N.B: We are _not_ going to add support for secret-based authentication.
     // It is insecure and strongly discouraged, so to encourage best practices
     // we should just not support it :)
-    let token =
-        azure_identity::create_default_credential().context("failed to create Azure credential")?;
+    Ok(match &config.credentials {
+        Some(kinds) => Arc::new(
+            credential::ChainedCredential::new(kinds)
+                .context("failed to create Azure credential chain")?,
+        ),
+        None => azure_identity::create_default_credential()
+            .context("failed to create Azure credential")?,
+    })
+}
 
-    // Run through every configured server and ensure they are reachable.
-    for server in &mut config.servers {
+/// Authenticate and prepare the shared application state, checking each upstream server along the way.
+async fn build_state(mut config: AppConfig) -> anyhow::Result<AppState> {
+    let credentials = Arc::new(credential::ReloadableCredential::new(create_credential(
+        &config,
+    )?));
+    let token: Arc<dyn TokenCredential> = credentials.clone();
+
+    let resolver = config
+        .dns
+        .as_ref()
+        .and_then(|d| d.cache_ttl)
+        .map(|ttl| Arc::new(dns::CachingResolver::new(Duration::from_secs(ttl))));
+    let client = http_client(&config, None, resolver.as_ref())?;
+
+    let realm_servers = config.realms.values_mut().flat_map(|r| &mut r.servers);
+    let source_servers = config.sources.iter_mut().flat_map(|s| &mut s.servers);
+    for server in config
+        .servers
+        .iter_mut()
+        .chain(realm_servers)
+        .chain(source_servers)
+    {
         // Ensure the URL ends with a trailing slash, as `url` will treat the last
         // segment as a filename without it.
         if !server.url.as_str().ends_with('/') {
@@ -467,35 +3494,480 @@ async fn main() -> anyhow::Result<()> {
             server.url = Url::from_str(&format!("{}/", server.url.as_str()))
                 .context("failed to append trailing slash to URL")?;
         }
+    }
 
+    // Run through every configured server and ensure they are reachable.
+    for server in config.all_servers() {
         // Send a request to the root of the symbol server. Ignore the response
         // since we are only interested in seeing if the symbol server responds.
-        if let Err(e) = reqwest::get(server.url.clone()).await {
+        if let Err(e) = send_request(&config, server, client.get(server.url.clone())).await {
             // Log the error, but do not abort startup since it's possible that
             // this could be a spurious network failure.
             error!(
                 "{:?}",
-                anyhow::Error::new(e)
-                    .context(format!("symbol server \"{}\" is unreachable", server.url))
+                e.context(format!("symbol server \"{}\" is unreachable", server.url))
             );
         }
 
         // Attempt to acquire a token upon startup just to surface any configuration errors early.
         if let Some(auth) = &server.auth {
             info!("acquiring token for server: {}", server.url);
-            let _tok = token
-                .get_token(&[&auth.scope])
-                .await
-                .with_context(|| format!("failed to get token for {}", server.url))?;
+
+            // If requested, hold off on serving requests until a token can be acquired rather
+            // than failing outright. This keeps readiness probes from passing before the proxy
+            // is actually capable of authenticating.
+            if config.warm_before_serve {
+                acquire_token_with_retry(token.as_ref(), &auth.scope, &server.url).await;
+            } else {
+                let _tok = token
+                    .get_token(&[&auth.scope])
+                    .await
+                    .with_context(|| format!("failed to get token for {}", server.url))?;
+            }
+        }
+    }
+
+    let global_limiter = config
+        .bandwidth_limit
+        .map(|rate| Arc::new(RateLimiter::new(rate)));
+    let server_limiters = server_limiters(&config.servers);
+    let server_clients = server_clients(&config, &config.servers, &client, resolver.as_ref())?;
+
+    let mirror_permits = Some(Arc::new(Semaphore::new(
+        config
+            .max_concurrent_mirrors
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_MIRRORS),
+    )));
+    let fetch_permits = config
+        .max_concurrent_fetches
+        .map(|n| FetchPermits(Arc::new(Semaphore::new(n))));
+
+    // Index the contents of any filesystem caches that have requested it.
+    let mut cache_indexes = HashMap::new();
+    let realm_caches = config.realms.values().filter_map(|r| r.cache.as_ref());
+    for cache in config.cache.iter().chain(realm_caches) {
+        if let ConfigCache::Fs(cache) = cache {
+            if cache.index && !cache_indexes.contains_key(&cache.path) {
+                let index = cache::FsIndex::build(cache)
+                    .await
+                    .with_context(|| format!("failed to index {}", cache.path.display()))?;
+                info!(
+                    "indexed {} symbols in {}",
+                    index.count(),
+                    cache.path.display()
+                );
+
+                cache_indexes.insert(cache.path.clone(), Arc::new(index));
+            }
+        }
+    }
+
+    // Clean up after any processes that exited while mirroring symbols, in the background.
+    if !config.disable_cache_writes {
+        let caches = config
+            .cache
+            .iter()
+            .chain(config.realms.values().filter_map(|r| r.cache.as_ref()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        tokio::spawn(async move {
+            for cache in caches {
+                match cache::sweep_partial(&cache, cache::STALE_PARTIAL_AGE).await {
+                    Ok(0) => {}
+                    Ok(n) => info!("removed {n} incomplete cache file(s)"),
+                    Err(e) => warn!("{:?}", e.context("failed to sweep incomplete cache files")),
+                }
+            }
+        });
+    }
+
+    let (prewarm, queue) = tokio::sync::mpsc::channel(
+        config
+            .prewarm_queue_size
+            .unwrap_or(DEFAULT_PREWARM_QUEUE_SIZE)
+            .max(1),
+    );
+
+    let state = AppState {
+        stats: Arc::new(Stats::new(config.servers.len())),
+        token,
+        credentials,
+        mirror_permits,
+        fetch_permits,
+        client,
+        server_clients: Arc::new(server_clients),
+        resolver,
+        global_limiter,
+        server_limiters: Arc::new(server_limiters),
+        request_counts: request_counts(&config),
+        negative_cache: negative_cache(&config, &config.servers),
+        pool_counters: Arc::new(pool_counters(&config.servers)),
+        shutdown: CancellationToken::new(),
+        cache_indexes: Arc::new(cache_indexes),
+        cache_writes: Arc::new(AtomicBool::new(!config.disable_cache_writes)),
+        prewarm,
+        mirrors: Arc::default(),
+        flights: Arc::default(),
+        config,
+    };
+
+    tokio::spawn(prewarm::run(state.clone(), queue));
+    Ok(state)
+}
+
+/// Create the table tracking how often symbols are requested, if a cache threshold is set.
+fn request_counts(config: &AppConfig) -> Option<Arc<Mutex<LruCache<String, u32>>>> {
+    let threshold = config.cache_threshold.as_ref()?;
+    let tracked = threshold
+        .tracked
+        .and_then(NonZeroUsize::new)
+        .unwrap_or(NonZeroUsize::new(10_000).unwrap());
+
+    Some(Arc::new(Mutex::new(LruCache::new(tracked))))
+}
+
+/// Count a request for the symbol at `path`, returning whether it has now been requested at least
+/// `threshold` times. Only the most recently requested symbols are tracked.
+fn record_request(counts: &Mutex<LruCache<String, u32>>, path: &str, threshold: u32) -> bool {
+    let mut counts = counts.lock().unwrap();
+    let count = counts.get_or_insert_mut(path.to_string(), || 0);
+    *count = count.saturating_add(1);
+
+    *count >= threshold
+}
+
+/// Create the request counters for each server pool among `servers`.
+fn pool_counters(servers: &[ConfigServer]) -> HashMap<String, AtomicU64> {
+    servers
+        .iter()
+        .filter_map(|s| s.pool.clone())
+        .map(|pool| (pool, AtomicU64::new(0)))
+        .collect()
+}
+
+/// Create the table of recent misses, if any of `servers` remembers them.
+fn negative_cache(
+    config: &AppConfig,
+    servers: &[ConfigServer],
+) -> Option<Arc<Mutex<LruCache<(usize, String), Instant>>>> {
+    if servers
+        .iter()
+        .all(|s| s.negative_ttl.or(config.negative_ttl).is_none())
+    {
+        return None;
+    }
+
+    Some(Arc::new(Mutex::new(LruCache::new(
+        NonZeroUsize::new(NEGATIVE_CACHE_CAPACITY).unwrap(),
+    ))))
+}
+
+/// Build the client used for each server, sharing `client` between those that do not override
+/// their timeouts.
+fn server_clients(
+    config: &AppConfig,
+    servers: &[ConfigServer],
+    client: &reqwest::Client,
+    resolver: Option<&Arc<dns::CachingResolver>>,
+) -> anyhow::Result<Vec<reqwest::Client>> {
+    servers
+        .iter()
+        .map(|s| match (s.connect_timeout, s.read_timeout) {
+            (None, None) => Ok(client.clone()),
+            _ => http_client(config, Some(s), resolver),
+        })
+        .collect()
+}
+
+/// Create the rate limiters for each of `servers`, in order.
+fn server_limiters(servers: &[ConfigServer]) -> Vec<Option<Arc<RateLimiter>>> {
+    servers
+        .iter()
+        .map(|s| {
+            s.bandwidth_limit
+                .map(|rate| Arc::new(RateLimiter::new(rate)))
+        })
+        .collect()
+}
+
+impl AppState {
+    /// Determine the order in which this state's servers (by index) are consulted for a request.
+    ///
+    /// Servers are consulted in configuration order, except that each pool is consulted as a
+    /// group at the position of its first server. Within a pool, the server consulted first is
+    /// chosen in weighted round-robin order, followed by the rest of the pool.
+    fn server_order(&self) -> Vec<usize> {
+        let servers = &self.config.servers;
+        let weight = |idx: usize| servers[idx].weight.unwrap_or(1);
+        let mut order = Vec::with_capacity(servers.len());
+
+        for (idx, server) in servers.iter().enumerate() {
+            let Some(pool) = &server.pool else {
+                order.push(idx);
+                continue;
+            };
+
+            // The pool has already been consulted at the position of an earlier member.
+            if servers[..idx].iter().any(|s| s.pool.as_ref() == Some(pool)) {
+                continue;
+            }
+
+            let members = (idx..servers.len())
+                .filter(|&i| servers[i].pool.as_ref() == Some(pool))
+                .collect::<Vec<_>>();
+
+            let total = members.iter().map(|&i| weight(i)).sum::<u64>();
+            let mut slot = self.pool_counters[pool].fetch_add(1, Ordering::Relaxed) % total;
+            let first = members
+                .iter()
+                .position(|&i| {
+                    if slot < weight(i) {
+                        return true;
+                    }
+
+                    slot -= weight(i);
+                    false
+                })
+                .unwrap_or(0);
+
+            order.extend(members[first..].iter().chain(&members[..first]));
+        }
+
+        order
+    }
+
+    /// The in-memory index of this state's cache, if it has one.
+    fn cache_index(&self) -> Option<&Arc<cache::FsIndex>> {
+        match &self.config.cache {
+            Some(ConfigCache::Fs(cache)) => self.cache_indexes.get(&cache.path),
+            _ => None,
+        }
+    }
+
+    /// Derive the state used to serve source files, which are fetched from their own servers but
+    /// share the cache, credentials and global limits with this state.
+    fn sources(&self, sources: &ConfigSources) -> anyhow::Result<AppState> {
+        let server_clients = server_clients(
+            &self.config,
+            &sources.servers,
+            &self.client,
+            self.resolver.as_ref(),
+        )?;
+
+        Ok(AppState {
+            config: AppConfig {
+                servers: sources.servers.clone(),
+                realms: HashMap::new(),
+                ..self.config.clone()
+            },
+            server_clients: Arc::new(server_clients),
+            server_limiters: Arc::new(server_limiters(&sources.servers)),
+            pool_counters: Arc::new(pool_counters(&sources.servers)),
+            ..self.clone()
+        })
+    }
+
+    /// Derive the state for a realm, which shares credentials and global limits with this state.
+    fn realm(&self, realm: &ConfigRealm) -> anyhow::Result<AppState> {
+        let server_clients = server_clients(
+            &self.config,
+            &realm.servers,
+            &self.client,
+            self.resolver.as_ref(),
+        )?;
+
+        Ok(AppState {
+            config: AppConfig {
+                cache: realm.cache.clone(),
+                servers: realm.servers.clone(),
+                realms: HashMap::new(),
+                ..self.config.clone()
+            },
+            server_clients: Arc::new(server_clients),
+            server_limiters: Arc::new(server_limiters(&realm.servers)),
+            request_counts: request_counts(&self.config),
+            negative_cache: negative_cache(&self.config, &realm.servers),
+            pool_counters: Arc::new(pool_counters(&realm.servers)),
+            stats: Arc::new(Stats::new(realm.servers.len())),
+            // N.B: Realms have their own caches, so their downloads must not be confused.
+            flights: Arc::default(),
+            ..self.clone()
+        })
+    }
+}
+
+/// Look up a single symbol and write it to `output`, reporting where it was found.
+async fn fetch(
+    mut state: AppState,
+    name1: String,
+    hash: String,
+    name2: String,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    if !valid_symbol_path(&name1, &hash, &name2) {
+        anyhow::bail!("invalid symbol path: {name1}/{hash}/{name2}");
+    }
+
+    let output = output.unwrap_or_else(|| PathBuf::from(&name2));
+
+    // N.B: Limit mirroring to a single permit so that we can wait for the mirror (if any) to
+    // complete before exiting.
+    let permits = Arc::new(Semaphore::new(1));
+    state.mirror_permits = Some(permits.clone());
+
+    let response = fetch_symbol(state, HeaderMap::new(), name1, hash, name2)
+        .await
+        .map_err(|e| e.0)?;
+
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("none")
+            .to_string()
+    };
+    let (source, server) = (header(UPSTREAM_SOURCE), header(UPSTREAM_SERVER));
+
+    if !response.status().is_success() {
+        anyhow::bail!("symbol not found (status {})", response.status());
+    }
+
+    let mut file = tokio::fs::File::create(&output)
+        .await
+        .with_context(|| format!("failed to create {}", output.display()))?;
+    let mut body = response.into_body().into_data_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("failed to read symbol")?;
+        file.write_all(&chunk)
+            .await
+            .context("failed to write symbol")?;
+    }
+    file.flush().await.context("failed to write symbol")?;
+
+    // Wait for mirroring to complete, if it was started.
+    let _permit = permits.acquire().await;
+
+    info!(
+        "wrote {} (source: {source}, server: {server})",
+        output.display()
+    );
+
+    Ok(())
+}
+
+/// Write a manifest of every entry in the cache to `output` (or stdout).
+async fn cache_export(config: AppConfig, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let cache = config.cache.as_ref().context("no cache is configured")?;
+    let token = create_credential(&config)?;
+
+    let manifest = cache::list(cache, token).await?;
+    let json = serde_json::to_vec_pretty(&manifest).context("failed to serialize manifest")?;
+
+    match &output {
+        Some(output) => tokio::fs::write(output, json)
+            .await
+            .with_context(|| format!("failed to write {}", output.display()))?,
+        None => tokio::io::stdout()
+            .write_all(&json)
+            .await
+            .context("failed to write manifest")?,
+    }
+
+    info!("exported {} cache entries", manifest.len());
+    Ok(())
+}
+
+/// Acquire a token for every authenticated upstream server, reporting each that fails.
+///
+/// N.B: The configuration itself has already been checked by `load_config`.
+async fn validate(config: AppConfig) -> anyhow::Result<()> {
+    let token = create_credential(&config)?;
+
+    let mut failed = 0;
+    for server in config.all_servers() {
+        if let Some(auth) = &server.auth {
+            info!("acquiring token for server: {}", server.url);
+
+            if let Err(e) = token.get_token(&[&auth.scope]).await {
+                error!(
+                    "{:?}",
+                    anyhow::Error::new(e)
+                        .context(format!("failed to get token for {}", server.url))
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    if failed != 0 {
+        anyhow::bail!("failed to get tokens for {failed} server(s)");
+    }
+
+    info!("configuration is valid");
+    Ok(())
+}
+
+/// Validate that every entry of a manifest written by `cache-export` is present in the cache.
+async fn cache_import(config: AppConfig, manifest: PathBuf) -> anyhow::Result<()> {
+    let cache = config.cache.as_ref().context("no cache is configured")?;
+    let token = create_credential(&config)?;
+
+    let data = tokio::fs::read(&manifest)
+        .await
+        .with_context(|| format!("failed to read {}", manifest.display()))?;
+    let manifest: Vec<cache::ManifestEntry> =
+        serde_json::from_slice(&data).context("failed to parse manifest")?;
+
+    let mut invalid = 0;
+    for entry in &manifest {
+        match cache::size(cache, token.clone(), &entry.path).await? {
+            None => {
+                warn!("{}: missing from the cache", entry.path);
+                invalid += 1;
+            }
+            Some(Some(size)) if entry.size.is_some_and(|s| s != size) => {
+                warn!(
+                    "{}: expected {} bytes, but the cache has {size}",
+                    entry.path,
+                    entry.size.unwrap_or_default()
+                );
+                invalid += 1;
+            }
+            Some(_) => {}
         }
     }
 
+    if invalid != 0 {
+        anyhow::bail!(
+            "{invalid} of {} cache entries are missing or invalid",
+            manifest.len()
+        );
+    }
+
+    info!("validated {} cache entries", manifest.len());
+    Ok(())
+}
+
+/// Determine whether `addr` is only reachable from this machine.
+fn is_loopback(addr: &SocketAddr) -> bool {
+    // N.B: Canonicalize the address so that IPv4-mapped IPv6 loopback addresses (`::ffff:127.0.0.1`)
+    // are recognized as loopback. Unspecified addresses (`0.0.0.0`, `::`) are never loopback.
+    addr.ip().to_canonical().is_loopback()
+}
+
+/// Listen for and serve symbol requests, and metrics if a recorder has been installed.
+async fn serve(state: AppState, metrics: Option<PrometheusHandle>) -> anyhow::Result<()> {
+    let config = &state.config;
     let addr = config
         .listen_address
         .unwrap_or(SocketAddr::from((Ipv4Addr::LOCALHOST, 5000)));
 
-    let has_auth = config.servers.iter().any(|s| s.auth.is_some());
-    if has_auth && !config.i_am_not_an_idiot && !addr.ip().is_loopback() {
+    let has_auth = config
+        .all_servers()
+        .any(|s| s.auth.is_some() || s.basic_auth.is_some());
+    if has_auth && !config.i_am_not_an_idiot && !is_loopback(&addr) {
         anyhow::bail!("You have configured the proxy to listen on a routable IP address with an upstream server that requires authentication, but `i_am_not_an_idiot` is still `false` in your configuration file. Read the documentation carefully before enabling the setting.");
     }
 
@@ -504,16 +3976,411 @@ async fn main() -> anyhow::Result<()> {
         .context("failed to bind address")?;
 
     // Set up the `axum` application with a single endpoint to handle symbol server requests.
-    let app = Router::new()
-        .route("/:name1/:hash/:name2", get(symbol))
-        .route("/health", get(health))
+    let mut app = symbol_routes(&state);
+    let mut ops = Router::new().route("/health", get(health));
+    if let Some(handle) = metrics {
+        ops = ops.route("/metrics", get(move || std::future::ready(handle.render())));
+    }
+
+    // Each realm is served under its own prefix, with its own servers and cache.
+    for (name, realm) in &state.config.realms {
+        info!("serving realm \"{name}\" under {}", realm.prefix);
+
+        app = app.nest(
+            &realm.prefix,
+            symbol_routes(&state).with_state(state.realm(realm)?),
+        );
+    }
+
+    // Source files are served under their own prefix, sharing the cache.
+    if let Some(sources) = &state.config.sources {
+        let prefix = sources.prefix.as_deref().unwrap_or(DEFAULT_SOURCE_PREFIX);
+        info!("serving source files under {prefix}");
+
+        app = app.nest(
+            prefix,
+            with_api_keys(&state, Router::new().route("/*path", get(source::source)))
+                .with_state(state.sources(sources)?),
+        );
+    }
+
+    // Administrative endpoints are only exposed if an admin token has been configured.
+    if state.config.admin_token.is_some() {
+        ops = ops.merge(
+            Router::new()
+                .route("/status", get(status::status))
+                .route("/config", get(admin::config))
+                .route("/cache/list", get(admin::list_cache))
+                .route("/cache/verify", post(admin::verify_cache))
+                .route("/admin/reload-credentials", post(admin::reload_credentials))
+                .route("/admin/cache/enable", post(admin::enable_cache_writes))
+                .route("/admin/cache/disable", post(admin::disable_cache_writes))
+                .route("/admin/prewarm", post(prewarm::enqueue))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_admin_token,
+                )),
+        );
+    }
+
+    // Health and administrative endpoints may be served on their own address (e.g. an internal-only
+    // interface), in which case the main address serves only symbol traffic.
+    if let Some(admin_addr) = state.config.admin_listen_address {
+        let admin_listener = TcpListener::bind(&admin_addr)
+            .await
+            .context("failed to bind admin address")?;
+        let ops = ops
+            .layer(TraceLayer::new_for_http())
+            .with_state(state.clone());
+
+        info!("serving administrative endpoints on {admin_addr}");
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(admin_listener, ops.into_make_service()).await {
+                error!("{:?}", anyhow::Error::new(e).context("admin server failed"));
+            }
+        });
+    } else {
+        app = app.merge(ops);
+    }
+
+    // Mount everything under the base path, if one is configured.
+    if let Some(base) = &state.config.base_path {
+        info!("serving under {base}");
+        app = Router::new().nest(base, app);
+    }
+
+    let stats = state.stats.clone();
+    let shutdown = state.shutdown.clone();
+    let mirrors = state.mirrors.clone();
+    let grace_period = state.config.shutdown_grace_period.map(Duration::from_secs);
+    let app = app
+        .fallback(malformed)
         .layer(TraceLayer::new_for_http())
-        .with_state(AppState { config, token });
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    // N.B: The readiness endpoint is added after the tracing layer, so that frequent probes do
+    // not drown out the request logs.
+    let healthz_path = format!(
+        "{}/healthz",
+        config.base_path.as_deref().unwrap_or_default()
+    );
+    let app = app.route(&healthz_path, get(healthz)).with_state(state);
 
     tracing::info!("listening on {addr}");
 
     // Serve the application :)
     axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal(
+            stats.clone(),
+            shutdown.clone(),
+            grace_period,
+        ))
+        .await
+        .context("failed to start server")?;
+
+    // Downloads (and mirrors) may outlive the requests that started them, e.g. if the client
+    // disconnected. The process must not exit underneath them.
+    mirrors.drain(&shutdown, MIRROR_ABORT_TIMEOUT).await;
+
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    info!(
+        "shutdown complete: {} requests and {} downloads interrupted, {} mirrors completed, {} mirrors aborted",
+        load(&stats.in_flight_requests),
+        load(&stats.in_flight_downloads),
+        load(&stats.mirrors_completed),
+        load(&stats.mirrors_aborted),
+    );
+
+    Ok(())
+}
+
+/// Wait for a request to shut down, logging the work still in flight when it arrives.
+///
+/// Once `grace_period` has elapsed after the request, `shutdown` is cancelled to abort any work
+/// that is still in flight.
+async fn shutdown_signal(
+    stats: Arc<Stats>,
+    shutdown: CancellationToken,
+    grace_period: Option<Duration>,
+) {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(
+                "{:?}",
+                anyhow::Error::new(e).context("failed to listen for shutdown signal")
+            );
+
+            // Never shut down if we are unable to listen for the signal.
+            std::future::pending::<()>().await;
+        }
+    };
+
+    // Service managers and container runtimes ask the process to stop with `SIGTERM`.
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!(
+                    "{:?}",
+                    anyhow::Error::new(e).context("failed to listen for SIGTERM")
+                );
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+
+    info!(
+        "shutting down: waiting for {} requests and {} downloads to complete",
+        stats.in_flight_requests.load(Ordering::Relaxed),
+        stats.in_flight_downloads.load(Ordering::Relaxed),
+    );
+
+    if let Some(grace_period) = grace_period {
+        tokio::spawn(async move {
+            tokio::time::sleep(grace_period).await;
+
+            warn!("shutdown grace period elapsed; cancelling in-flight requests and mirrors");
+            shutdown.cancel();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PDB: &[u8] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+
+    /// Build a cabinet holding a single file named `name`.
+    fn cabinet(name: &str, data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut builder = cab::CabinetBuilder::new();
+        builder
+            .add_folder(cab::CompressionType::None)
+            .add_file(name);
+
+        let mut writer = builder.build(std::io::Cursor::new(Vec::new())).unwrap();
+        while let Some(mut file) = writer.next_file().unwrap() {
+            file.write_all(data).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Start a classic symbol store that only holds `foo.pdb` compressed, returning its URL.
+    async fn compressed_store() -> Url {
+        let cab = Bytes::from(cabinet("foo.pdb", PDB));
+        let app = Router::new().route(
+            "/foo.pdb/0123456789ABCDEF1/foo.pd_",
+            get(move || std::future::ready(cab.clone())),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    /// Request `foo.pdb` through a proxy caching to `dir` in front of `server`.
+    async fn fetch_compressed(server: &Url, dir: &std::path::Path, exclude: &[&str]) -> Response {
+        let toml = format!(
+            r#"
+i_am_not_an_idiot = false
+cache_exclude = {exclude:?}
+
+[cache]
+type = "fs"
+path = {path:?}
+
+[[servers]]
+url = "{server}"
+compressed_fallback = true
+"#,
+            path = dir.display().to_string(),
+        );
+
+        let config: AppConfig = Figment::new()
+            .merge(figment::providers::Toml::string(&toml))
+            .extract()
+            .unwrap();
+        let state = build_state(config).await.unwrap();
+
+        fetch_symbol(
+            state,
+            HeaderMap::new(),
+            "foo.pdb".to_string(),
+            "0123456789ABCDEF1".to_string(),
+            "foo.pdb".to_string(),
+        )
         .await
-        .context("failed to start server")
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn compressed_symbols_are_mirrored() {
+        let server = compressed_store().await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let response = fetch_compressed(&server, dir.path(), &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], PDB);
+
+        assert!(dir
+            .path()
+            .join("foo.pdb/0123456789ABCDEF1/foo.pdb")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn excluded_compressed_symbols_are_not_mirrored() {
+        let server = compressed_store().await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let response = fetch_compressed(&server, dir.path(), &["foo.pdb/*"]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], PDB);
+
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    fn counts(tracked: usize) -> Mutex<LruCache<String, u32>> {
+        Mutex::new(LruCache::new(NonZeroUsize::new(tracked).unwrap()))
+    }
+
+    #[test]
+    fn threshold_reached() {
+        let counts = counts(10);
+
+        assert!(!record_request(&counts, "a", 3));
+        assert!(!record_request(&counts, "a", 3));
+        assert!(!record_request(&counts, "b", 3));
+        assert!(record_request(&counts, "a", 3));
+        assert!(record_request(&counts, "a", 3));
+    }
+
+    #[test]
+    fn threshold_forgets_least_recent() {
+        let counts = counts(2);
+
+        assert!(!record_request(&counts, "a", 2));
+        assert!(!record_request(&counts, "b", 2));
+        // Tracking `c` evicts `a`, the least recently requested symbol, whose count starts over.
+        assert!(!record_request(&counts, "c", 2));
+        assert!(!record_request(&counts, "a", 2));
+        assert!(record_request(&counts, "a", 2));
+        assert!(!record_request(&counts, "b", 2));
+    }
+
+    #[test]
+    fn readonly_check_creates_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("a/b");
+
+        check_not_readonly(&missing).unwrap();
+        assert!(!dir.path().join("a").exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn readonly_check_rejects_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        assert!(check_not_readonly(&file.join("cache")).is_err());
+    }
+
+    #[test]
+    fn writable_check_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+
+        check_writable(&cache).unwrap();
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn components_cannot_name_schemes() {
+        assert!(valid_component("foo.pdb"));
+        assert!(!valid_component("http:evil.com"));
+        assert!(!valid_symbol_path(
+            "http:evil.com",
+            "0123456789ABCDEF1",
+            "foo.pdb"
+        ));
+    }
+
+    #[test]
+    fn urls_are_confined() {
+        let base = Url::parse("https://symbols.example.com/store/").unwrap();
+
+        let url = base.join("foo.pdb/0123456789ABCDEF1/foo.pdb").unwrap();
+        assert!(confine_url(&base, url).is_ok());
+
+        // WHATWG URL semantics resolve a component naming another scheme as an absolute URL.
+        let url = base
+            .join("http:evil.com/0123456789ABCDEF1/foo.pdb")
+            .unwrap();
+        assert_eq!(url.host_str(), Some("evil.com"));
+        assert!(confine_url(&base, url).is_err());
+
+        let url = base.join("//evil.com/foo.pdb").unwrap();
+        assert!(confine_url(&base, url).is_err());
+
+        let url = base.join("/other/foo.pdb").unwrap();
+        assert!(confine_url(&base, url).is_err());
+    }
+
+    fn loopback(addr: &str) -> bool {
+        is_loopback(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_loopback() {
+        assert!(loopback("127.0.0.1:5000"));
+    }
+
+    #[test]
+    fn ipv6_loopback() {
+        assert!(loopback("[::1]:5000"));
+    }
+
+    #[test]
+    fn ipv4_mapped_loopback() {
+        assert!(loopback("[::ffff:127.0.0.1]:5000"));
+    }
+
+    #[test]
+    fn routable_addresses() {
+        assert!(!loopback("10.0.0.1:5000"));
+        assert!(!loopback("[2001:db8::1]:5000"));
+        assert!(!loopback("[::ffff:10.0.0.1]:5000"));
+    }
+
+    #[test]
+    fn unspecified_addresses() {
+        assert!(!loopback("0.0.0.0:5000"));
+        assert!(!loopback("[::]:5000"));
+    }
 }
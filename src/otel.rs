@@ -0,0 +1,46 @@
+//! OpenTelemetry trace export.
+use anyhow::Context;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    trace::{Config, Sampler},
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::ConfigOtel;
+
+/// Build a layer exporting `tracing` spans to the configured OTLP endpoint.
+pub fn layer<S>(config: &ConfigOtel) -> anyhow::Result<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let sampler = match config.sample_rate {
+        Some(rate) => Sampler::TraceIdRatioBased(rate),
+        None => Sampler::AlwaysOn,
+    };
+
+    let provider =
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&config.endpoint),
+            )
+            .with_trace_config(Config::default().with_sampler(sampler).with_resource(
+                Resource::new([KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]),
+            ))
+            .install_batch(opentelemetry_sdk::runtime::Tokio)
+            .context("failed to install OTLP exporter")?;
+
+    opentelemetry::global::set_tracer_provider(provider.clone());
+
+    Ok(tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))))
+}
+
+/// Flush any spans that have not yet been exported.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
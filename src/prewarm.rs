@@ -0,0 +1,137 @@
+//! Background mirroring of the symbols listed in a manifest.
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use reqwest::StatusCode;
+use serde_json::json;
+use tokio::sync::{mpsc::Receiver, Semaphore};
+use tracing::{debug, error, info, warn};
+
+use crate::{cache, status::Stats, throttle, AppState};
+
+/// Endpoint used by operators (or CI) to queue the symbols in a manifest for mirroring.
+pub async fn enqueue(
+    State(state): State<AppState>,
+    Json(manifest): Json<Vec<cache::ManifestEntry>>,
+) -> Response {
+    if state.config.cache.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "prewarming requires a cache to be configured",
+        )
+            .into_response();
+    }
+
+    // Symbols that do not fit in the queue are shed rather than waited for.
+    let (mut queued, mut dropped) = (0, 0);
+    for entry in manifest {
+        match state.prewarm.try_send(entry.path) {
+            Ok(()) => {
+                Stats::add(&state.stats.prewarm_queued, 1);
+                queued += 1;
+            }
+            Err(_) => {
+                Stats::add(&state.stats.prewarm_dropped, 1);
+                dropped += 1;
+            }
+        }
+    }
+
+    info!("queued {queued} symbols for prewarming");
+    if dropped != 0 {
+        warn!("prewarm queue is full; dropped {dropped} symbols");
+    }
+
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "queued": queued, "dropped": dropped })),
+    )
+        .into_response()
+}
+
+/// Mirror the symbols sent to `queue` into the cache, at most `prewarm_concurrency` at a time.
+pub async fn run(state: AppState, mut queue: Receiver<String>) {
+    let permits = Arc::new(Semaphore::new(
+        state.config.prewarm_concurrency.unwrap_or(1).max(1),
+    ));
+
+    while let Some(path) = queue.recv().await {
+        // N.B: The semaphore is never closed, so acquiring a permit cannot fail.
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let stats = state.stats.clone();
+
+            match prewarm(state, &path).await {
+                Ok(()) => Stats::add(&stats.prewarm_completed, 1),
+                Err(e) => {
+                    error!("{:?}", e.context(format!("failed to prewarm {path}")));
+                    Stats::add(&stats.prewarm_failed, 1);
+                }
+            }
+        });
+    }
+}
+
+/// Mirror a single symbol (`name1/hash/name2`) into the cache, if it is not already cached.
+async fn prewarm(state: AppState, path: &str) -> anyhow::Result<()> {
+    let cache = state
+        .config
+        .cache
+        .as_ref()
+        .context("no cache is configured")?;
+
+    // N.B: If cache reads are disabled, the cache is not consulted and the symbol is mirrored anew.
+    if !state.config.disable_cache_reads
+        && cache::size(cache, state.token.clone(), path)
+            .await?
+            .is_some()
+    {
+        debug!("{path} is already cached");
+        return Ok(());
+    }
+
+    let mut parts = path.splitn(3, '/');
+    let (Some(name1), Some(hash), Some(name2)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("malformed symbol path");
+    };
+    if !crate::valid_symbol_path(name1, hash, name2) {
+        anyhow::bail!("invalid symbol path");
+    }
+
+    let response = crate::fetch_symbol(
+        state.clone(),
+        HeaderMap::new(),
+        name1.to_string(),
+        hash.to_string(),
+        name2.to_string(),
+    )
+    .await
+    .map_err(|e| e.0)?;
+    if !response.status().is_success() {
+        anyhow::bail!("symbol could not be fetched: {}", response.status());
+    }
+
+    // Consume the symbol so that it is mirrored, without exceeding the global bandwidth limit.
+    let limiters = state.global_limiter.iter().cloned().collect();
+    let mut body = Box::pin(throttle::throttle(
+        response.into_body().into_data_stream(),
+        limiters,
+    ));
+    while let Some(chunk) = body.next().await {
+        chunk.context("failed to read symbol")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Resumption of interrupted upstream downloads.
+use std::{pin::Pin, time::Duration};
+
+use anyhow::Context;
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest::{header, StatusCode};
+use tracing::{error, warn};
+use url::Url;
+
+use crate::{send_request, upstream_request, AppState};
+
+type ByteStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+/// Everything needed to request the remainder of an interrupted download.
+pub struct Resume {
+    pub state: AppState,
+    /// The index of the server the download was started from
+    pub idx: usize,
+    pub url: Url,
+    pub body: Option<String>,
+    /// The timeout applied to each resumed request, as for the original request
+    pub timeout: Option<Duration>,
+    /// The maximum number of times the download may be resumed
+    pub attempts: u32,
+}
+
+impl Resume {
+    /// Request the remainder of the symbol, starting at `offset`.
+    async fn request(&self, offset: u64) -> anyhow::Result<reqwest::Response> {
+        let server = &self.state.config.servers[self.idx];
+        let mut req = upstream_request(
+            &self.state.server_clients[self.idx],
+            server,
+            self.state.token.as_ref(),
+            &self.url,
+            self.body.as_deref(),
+        )
+        .await?
+        .header(header::RANGE, format!("bytes={offset}-"));
+        if let Some(timeout) = self.timeout {
+            req = req.timeout(timeout);
+        }
+
+        let res = send_request(&self.state.config, server, req).await?;
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!("upstream did not honor the range request: {}", res.status());
+        }
+
+        // N.B: Make sure the upstream resumed where we asked it to, or the symbol would be corrupted.
+        let start = res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes "))
+            .and_then(|v| v.split('-').next())
+            .and_then(|v| v.parse::<u64>().ok())
+            .context("upstream did not report the resumed range")?;
+        if start != offset {
+            anyhow::bail!("upstream resumed at byte {start} rather than {offset}");
+        }
+
+        Ok(res)
+    }
+}
+
+/// Wraps an upstream body stream such that, if it fails partway through, the remainder of the
+/// symbol is requested with a ranged request and streamed in its place.
+///
+/// If the download cannot be resumed, the original error is yielded and the stream ends.
+pub fn resumable(
+    stream: ByteStream,
+    resume: Resume,
+) -> impl Stream<Item = reqwest::Result<Bytes>> + Send {
+    let state = (stream, Some(resume), 0u64, 0u32);
+
+    futures::stream::unfold(
+        state,
+        |(mut stream, resume, mut received, mut attempts)| async move {
+            loop {
+                let e = match stream.next().await? {
+                    Ok(chunk) => {
+                        received += chunk.len() as u64;
+                        return Some((Ok(chunk), (stream, resume, received, attempts)));
+                    }
+                    Err(e) => e,
+                };
+
+                let ended: ByteStream = Box::pin(futures::stream::empty());
+                let Some(r) = resume.as_ref().filter(|r| attempts < r.attempts) else {
+                    return Some((Err(e), (ended, None, received, attempts)));
+                };
+
+                attempts += 1;
+                warn!(
+                    "download of {} interrupted after {received} bytes; resuming (attempt {attempts})",
+                    r.url
+                );
+
+                match r.request(received).await {
+                    Ok(res) => stream = Box::pin(res.bytes_stream()),
+                    Err(err) => {
+                        error!(
+                            "{:?}",
+                            err.context(format!("failed to resume download of {}", r.url))
+                        );
+                        return Some((Err(e), (ended, None, received, attempts)));
+                    }
+                }
+            }
+        },
+    )
+}
@@ -0,0 +1,215 @@
+//! Proxying of the source files referenced by source-indexed PDBs.
+//!
+//! Source files are fetched from their own upstream servers, but share the symbol cache (under
+//! `SOURCE_CACHE_PREFIX`) and the upstream authentication settings.
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use reqwest::{header, StatusCode};
+use sha2::{Digest, Sha256};
+use tracing::{debug, error};
+
+use crate::{
+    accepts_encoding, cache_lookup, cache_response, confine_url, content_type, encode_digest,
+    host_allowed, mirror_complete, not_found_response, send_request, status::Stats, throttle,
+    upstream_request, valid_component, AppState, Error, REPR_DIGEST, UPSTREAM_SOURCE,
+};
+
+/// The prefix under which source files are stored in the cache.
+const SOURCE_CACHE_PREFIX: &str = "_sources";
+
+/// The largest source file that is buffered (and mirrored) in full before being served.
+const MAX_BUFFERED_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Determine whether `path` is a plausible source file path, which cannot escape the cache.
+fn valid_path(path: &str) -> bool {
+    path.split('/').all(valid_component)
+}
+
+/// Endpoint used by debuggers to fetch the source files referenced by a source-indexed PDB.
+pub async fn source(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(path): Path<String>,
+) -> Result<Response, Error> {
+    if !valid_path(&path) {
+        return Ok((StatusCode::BAD_REQUEST, "malformed source file path").into_response());
+    }
+
+    let config = &state.config;
+    if config.sources.is_none() {
+        return Ok(not_found_response(config)?);
+    }
+
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let cache_path = format!("{SOURCE_CACHE_PREFIX}/{path}");
+    let accept_zstd = accepts_encoding(&headers, "zstd");
+
+    if let Some(cache) = &config.cache {
+        if let Some(hit) = cache_lookup(&state, cache, &cache_path, accept_zstd).await? {
+            Stats::add(&state.stats.cache_hits, 1);
+            return Ok(cache_response(
+                hit,
+                content_type(name, &config.content_types),
+            )?);
+        }
+    }
+
+    // N.B: This state is derived for the source file servers, so `config.servers` names them.
+    'servers: for (idx, server) in config.servers.iter().enumerate() {
+        let url = server
+            .url
+            .join(&path)
+            .context("failed to build request url")?;
+        let Ok(url) = confine_url(&server.url, url) else {
+            debug!(
+                "refusing to serve {path}, as it would escape {}",
+                server.url
+            );
+            return Ok((StatusCode::BAD_REQUEST, "malformed source file path").into_response());
+        };
+
+        // Never send requests (or credentials) to a host outside of the allow-list.
+        if !host_allowed(config.allowed_upstream_hosts.as_deref(), &url) {
+            error!(
+                "refusing to send request to {url}, as its host is not in `allowed_upstream_hosts`"
+            );
+            continue;
+        }
+
+        let result = async {
+            let req = upstream_request(
+                &state.server_clients[idx],
+                server,
+                state.token.as_ref(),
+                &url,
+                None,
+            )
+            .await?;
+            let res = send_request(config, server, req).await?;
+            if !res.status().is_success() {
+                debug!("{url} responded with {}", res.status());
+                return anyhow::Ok(None);
+            }
+
+            Ok(Some(res))
+        }
+        .await;
+
+        let res = match result {
+            Ok(Some(res)) => res,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("{:?}", e.context(format!("failed to fetch {url}")));
+                continue;
+            }
+        };
+
+        let content_length = res.content_length();
+        let limiters = state.server_limiters[idx]
+            .iter()
+            .chain(&state.global_limiter)
+            .cloned()
+            .collect();
+        let mut stream = Box::pin(throttle::throttle(res.bytes_stream(), limiters));
+
+        // Source files are usually small, so they are buffered and mirrored in full before being
+        // served. Those too large to mirror are streamed straight through instead.
+        let limit = config
+            .max_cache_file_size
+            .map_or(MAX_BUFFERED_SIZE, |max| max.min(MAX_BUFFERED_SIZE));
+        let mut buffer = Vec::new();
+        let complete = loop {
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    buffer.extend_from_slice(&chunk);
+                    if buffer.len() as u64 > limit {
+                        break false;
+                    }
+                }
+                Some(Err(e)) => {
+                    let e = anyhow::Error::new(e).context("failed to read response body");
+                    error!("{:?}", e.context(format!("failed to fetch {url}")));
+                    continue 'servers;
+                }
+                None => break true,
+            }
+        };
+
+        let mut response = Response::builder()
+            .header(UPSTREAM_SOURCE, "server")
+            .header(
+                header::CONTENT_TYPE,
+                content_type(name, &config.content_types),
+            );
+        Stats::add(&state.stats.upstream_hits, 1);
+
+        if !complete {
+            debug!("{url} is too large to mirror; streaming it instead");
+
+            if let Some(len) = content_length {
+                response = response.header(header::CONTENT_LENGTH, len);
+            }
+
+            let head = Bytes::from(buffer);
+            let body = futures::stream::once(async move { Ok(head) }).chain(stream);
+            return Ok(response
+                .body(Body::from_stream(body))
+                .context("failed to build response body")?);
+        }
+
+        let body = Bytes::from(buffer);
+        let sha256 = config
+            .content_digest
+            .then(|| encode_digest(Sha256::new_with_prefix(&body)));
+
+        mirror_complete(
+            &state,
+            config.cache.as_ref(),
+            &cache_path,
+            true,
+            &url,
+            &body,
+            sha256.clone(),
+        )
+        .await;
+
+        if let Some(sha256) = sha256 {
+            response = response.header(REPR_DIGEST, format!("sha-256=:{sha256}:"));
+        }
+
+        return Ok(response
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .context("failed to build response body")?);
+    }
+
+    Ok(not_found_response(config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert!(valid_path("src/lib.rs"));
+        assert!(!valid_path("src/../../etc/passwd"));
+        assert!(!valid_path("/etc/passwd"));
+    }
+
+    /// A leading `scheme:host` segment would otherwise turn the request into an absolute URL.
+    #[test]
+    fn paths_cannot_name_schemes() {
+        assert!(!valid_path("http:evil.com/src/lib.rs"));
+
+        let base = url::Url::parse("https://sources.example.com/repo/").unwrap();
+        let url = base.join("http:evil.com/src/lib.rs").unwrap();
+        assert!(confine_url(&base, url).is_err());
+    }
+}
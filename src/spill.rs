@@ -0,0 +1,199 @@
+//! Spilling of symbols to disk when a client cannot keep up with the upstream server.
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use axum::body::Bytes;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{
+        mpsc::{error::TrySendError, Sender},
+        watch,
+    },
+};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// The size of the chunks read back from a spill file.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file holding the part of a symbol that the client has not yet received.
+struct Spill {
+    file: File,
+    /// The number of bytes written to the file
+    written: u64,
+    /// Notifies the reader of the number of bytes written. Dropping this signals the end of the symbol.
+    progress: watch::Sender<u64>,
+    /// The error that ended the symbol early, if any, forwarded to the client once it has caught up
+    error: Arc<Mutex<Option<reqwest::Error>>>,
+}
+
+impl Spill {
+    /// Create a spill file in `dir`, and begin streaming it to `tx`.
+    async fn create(dir: &Path, tx: Sender<reqwest::Result<Bytes>>) -> anyhow::Result<Self> {
+        let path = dir.join(format!("spill-{}", Uuid::new_v4()));
+        let file = File::create(&path)
+            .await
+            .with_context(|| format!("failed to create {}", path.display()))?;
+
+        debug!(
+            "client cannot keep up; spilling the rest of the symbol to {}",
+            path.display()
+        );
+        metrics::counter!("symbols_spilled_total").increment(1);
+
+        let (progress, rx) = watch::channel(0);
+        let error = Arc::new(Mutex::new(None));
+
+        tokio::spawn(drain(path, rx, error.clone(), tx));
+
+        Ok(Self {
+            file,
+            written: 0,
+            progress,
+            error,
+        })
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(chunk).await?;
+        // N.B: Writes only reach the file once flushed, and the reader must not overtake them.
+        self.file.flush().await?;
+
+        self.written += chunk.len() as u64;
+        self.progress.send_replace(self.written);
+        Ok(())
+    }
+}
+
+/// Stream the spill file at `path` to the client as it is written, removing it afterwards.
+async fn drain(
+    path: PathBuf,
+    mut progress: watch::Receiver<u64>,
+    error: Arc<Mutex<Option<reqwest::Error>>>,
+    tx: Sender<reqwest::Result<Bytes>>,
+) {
+    let result = async {
+        let mut file = File::open(&path)
+            .await
+            .context("failed to open spill file")?;
+        let mut buf = vec![0; READ_CHUNK_SIZE];
+        let mut read = 0u64;
+
+        loop {
+            let written = *progress.borrow_and_update();
+            if read < written {
+                let len = (written - read).min(buf.len() as u64) as usize;
+                file.read_exact(&mut buf[..len])
+                    .await
+                    .context("failed to read spill file")?;
+                read += len as u64;
+
+                if tx
+                    .send(Ok(Bytes::copy_from_slice(&buf[..len])))
+                    .await
+                    .is_err()
+                {
+                    // The client has disconnected.
+                    return anyhow::Ok(());
+                }
+
+                continue;
+            }
+
+            // Once the writer is gone, nothing more will be written.
+            if progress.changed().await.is_err() && read == *progress.borrow() {
+                break;
+            }
+        }
+
+        let e = error.lock().unwrap().take();
+        if let Some(e) = e {
+            let _ = tx.send(Err(e)).await;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        error!("{:?}", e.context("failed to stream spilled symbol"));
+    }
+
+    let _ = tokio::fs::remove_file(&path).await;
+}
+
+/// Sends a symbol to the client as it is mirrored.
+///
+/// If spilling is enabled and the client falls behind, the rest of the symbol is written to a file
+/// in the spill directory and streamed to the client from there, so that neither mirroring stalls
+/// nor the symbol accumulates in memory.
+pub struct ClientSink {
+    tx: Sender<reqwest::Result<Bytes>>,
+    /// The directory to spill to, if spilling is enabled
+    dir: Option<PathBuf>,
+    spill: Option<Spill>,
+}
+
+impl ClientSink {
+    pub fn new(tx: Sender<reqwest::Result<Bytes>>, dir: Option<PathBuf>) -> Self {
+        Self {
+            tx,
+            dir,
+            spill: None,
+        }
+    }
+
+    /// Send the next chunk of the symbol. Returns `false` if the client can no longer receive it.
+    pub async fn send(&mut self, chunk: Bytes) -> bool {
+        if self.spill.is_none() {
+            match self.tx.try_send(Ok(chunk.clone())) {
+                Ok(()) => return true,
+                Err(TrySendError::Closed(_)) => return false,
+                Err(TrySendError::Full(_)) => {}
+            }
+
+            let Some(dir) = &self.dir else {
+                return self.tx.send(Ok(chunk)).await.is_ok();
+            };
+
+            match Spill::create(dir, self.tx.clone()).await {
+                Ok(spill) => self.spill = Some(spill),
+                Err(e) => {
+                    error!("{:?}", e.context("failed to spill symbol to disk"));
+                    self.dir = None;
+
+                    return self.tx.send(Ok(chunk)).await.is_ok();
+                }
+            }
+        }
+
+        if let Some(spill) = &mut self.spill {
+            if let Err(e) = spill.write(&chunk).await {
+                // N.B: The client cannot be sent the rest of the symbol in order now.
+                error!("{:?}", e.context("failed to spill symbol to disk"));
+                return false;
+            }
+        }
+
+        !self.tx.is_closed()
+    }
+
+    /// Wait for the client to disconnect.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    /// End the symbol with an error, once the client has received everything before it.
+    pub async fn fail(self, e: reqwest::Error) {
+        match self.spill {
+            Some(spill) => *spill.error.lock().unwrap() = Some(e),
+            None => {
+                let _ = self.tx.send(Err(e)).await;
+            }
+        }
+    }
+}
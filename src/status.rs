@@ -0,0 +1,341 @@
+//! Runtime statistics and the `/status` endpoint.
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use axum::{extract::State, Json};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{AppConfig, ConfigCache};
+
+/// The observed health of an upstream server.
+#[derive(Default, Serialize)]
+pub struct ServerHealth {
+    /// The status code returned by the most recent request to this server
+    pub last_status: Option<u16>,
+    /// The error encountered by the most recent request to this server, if it failed
+    pub last_error: Option<String>,
+    /// The number of consecutive requests to this server that failed to complete
+    pub consecutive_failures: u64,
+}
+
+/// Statistics collected over the lifetime of the application.
+#[derive(Default)]
+pub struct Stats {
+    /// The number of symbol requests received
+    pub requests: AtomicU64,
+    /// The number of symbol requests served from the cache
+    pub cache_hits: AtomicU64,
+    /// The number of symbol requests served from the local store
+    pub local_hits: AtomicU64,
+    /// The number of symbol requests served from an upstream server
+    pub upstream_hits: AtomicU64,
+    /// The number of symbol requests that could not be satisfied
+    pub misses: AtomicU64,
+    /// The number of bytes served to clients
+    pub bytes_served: AtomicU64,
+    /// The number of bytes written to the cache
+    pub bytes_cached: AtomicU64,
+    /// The number of symbol requests currently being handled
+    pub in_flight_requests: AtomicU64,
+    /// The number of symbol downloads from upstream servers currently in progress
+    pub in_flight_downloads: AtomicU64,
+    /// The largest number of concurrent downloads observed
+    pub peak_in_flight_downloads: AtomicU64,
+    /// The number of requests that were coalesced onto an existing download
+    pub coalesced: AtomicU64,
+    /// The number of symbols successfully mirrored to the cache
+    pub mirrors_completed: AtomicU64,
+    /// The number of symbols whose mirroring was started but abandoned
+    pub mirrors_aborted: AtomicU64,
+    /// The number of symbols queued for prewarming
+    pub prewarm_queued: AtomicU64,
+    /// The number of queued symbols that have been prewarmed (or were already cached)
+    pub prewarm_completed: AtomicU64,
+    /// The number of queued symbols that could not be prewarmed
+    pub prewarm_failed: AtomicU64,
+    /// The number of symbols not queued for prewarming because the queue was full
+    pub prewarm_dropped: AtomicU64,
+    /// The health of each configured upstream server, in configuration order
+    pub servers: Vec<Mutex<ServerHealth>>,
+}
+
+impl Stats {
+    pub fn new(servers: usize) -> Self {
+        Self {
+            servers: (0..servers).map(|_| Mutex::default()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Increment a counter by `n`.
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Note the start of a symbol request. The request is considered complete once the returned
+    /// guard is dropped.
+    pub fn begin_request(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight_requests.fetch_add(1, Ordering::Relaxed);
+
+        RequestGuard(self.clone())
+    }
+
+    /// Note the start of a download from an upstream server. The download is considered complete
+    /// once the returned guard is dropped.
+    pub fn begin_download(self: &Arc<Self>) -> DownloadGuard {
+        let current = self.in_flight_downloads.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_in_flight_downloads
+            .fetch_max(current, Ordering::Relaxed);
+        metrics::gauge!("downloads_in_flight").increment(1.0);
+
+        DownloadGuard(self.clone())
+    }
+
+    /// Record the status code returned by the server at index `server`.
+    pub fn record_status(&self, server: usize, status: StatusCode) {
+        let mut health = self.servers[server].lock().unwrap();
+        health.last_status = Some(status.as_u16());
+        health.last_error = None;
+        health.consecutive_failures = 0;
+    }
+
+    /// Record a failure to complete a request to the server at index `server`.
+    pub fn record_error(&self, server: usize, error: &anyhow::Error) {
+        let mut health = self.servers[server].lock().unwrap();
+        health.last_status = None;
+        health.last_error = Some(format!("{error:#}"));
+        health.consecutive_failures += 1;
+    }
+}
+
+/// Tracks an in-progress request. See [`Stats::begin_request`].
+pub struct RequestGuard(Arc<Stats>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Tracks an in-progress download. See [`Stats::begin_download`].
+pub struct DownloadGuard(Arc<Stats>);
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        self.0.in_flight_downloads.fetch_sub(1, Ordering::Relaxed);
+        metrics::gauge!("downloads_in_flight").decrement(1.0);
+    }
+}
+
+/// Tracks the background tasks streaming symbols from upstream servers (and mirroring them to the
+/// cache), so that shutdown can wait for them to complete.
+#[derive(Default)]
+pub struct MirrorTasks {
+    tasks: TaskTracker,
+    /// The cache paths of the symbols currently being streamed
+    paths: Mutex<Vec<String>>,
+}
+
+impl MirrorTasks {
+    /// Spawn `task`, which streams the symbol at `path`. Errors are logged, as there is nobody
+    /// else to report them to.
+    pub fn spawn<F>(self: &Arc<Self>, path: String, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.paths.lock().unwrap().push(path.clone());
+
+        let guard = MirrorGuard(self.clone(), path);
+        self.tasks.spawn(async move {
+            if let Err(e) = task.await {
+                error!("{:?}", e.context(format!("failed to stream {}", guard.1)));
+            }
+        });
+    }
+
+    /// Wait for every task to complete. Once `shutdown` is cancelled, the tasks are given
+    /// `timeout` to abort before the symbols they left behind are logged and given up on.
+    pub async fn drain(&self, shutdown: &CancellationToken, timeout: Duration) {
+        self.tasks.close();
+        if self.tasks.is_empty() {
+            return;
+        }
+
+        info!(
+            "waiting for {} symbol downloads to complete",
+            self.tasks.len()
+        );
+
+        tokio::select! {
+            _ = self.tasks.wait() => {}
+            _ = async {
+                shutdown.cancelled().await;
+                tokio::time::sleep(timeout).await;
+            } => {
+                let paths = self.paths.lock().unwrap();
+                warn!(
+                    "gave up waiting on {} symbol downloads; their cache entries may be left behind partially written: {}",
+                    paths.len(),
+                    paths.join(", ")
+                );
+            }
+        }
+    }
+}
+
+/// Removes a path from [`MirrorTasks`] once its task completes (or panics).
+struct MirrorGuard(Arc<MirrorTasks>, String);
+
+impl Drop for MirrorGuard {
+    fn drop(&mut self) {
+        let mut paths = self.0.paths.lock().unwrap();
+        if let Some(i) = paths.iter().position(|p| *p == self.1) {
+            paths.swap_remove(i);
+        }
+    }
+}
+
+/// Measures the time taken to receive a symbol from an upstream server, recording it once the
+/// transfer completes (or is abandoned).
+pub struct TransferTimer {
+    /// The URL the symbol is being downloaded from
+    url: Url,
+    /// The server the symbol is being downloaded from, used to label metrics
+    server: String,
+    /// The time at which the request for the symbol was received
+    received: Instant,
+    /// The time at which the first byte of the symbol was received
+    first_byte: Option<Instant>,
+    /// The number of bytes received so far
+    bytes: u64,
+}
+
+impl TransferTimer {
+    pub fn new(url: Url, server: &Url, received: Instant) -> Self {
+        Self {
+            url,
+            server: server.to_string(),
+            received,
+            first_byte: None,
+            bytes: 0,
+        }
+    }
+
+    /// Note the receipt of a chunk of `len` bytes.
+    pub fn chunk(&mut self, len: usize) {
+        if self.first_byte.is_none() {
+            let first_byte = Instant::now();
+            let ttfb = first_byte - self.received;
+
+            metrics::histogram!("upstream_time_to_first_byte_seconds", "server" => self.server.clone())
+                .record(ttfb.as_secs_f64());
+            self.first_byte = Some(first_byte);
+        }
+
+        self.bytes += len as u64;
+    }
+}
+
+impl Drop for TransferTimer {
+    fn drop(&mut self) {
+        let Some(first_byte) = self.first_byte else {
+            debug!("{}: no data received", self.url);
+            return;
+        };
+
+        let transfer = first_byte.elapsed();
+        metrics::histogram!("upstream_transfer_seconds", "server" => self.server.clone())
+            .record(transfer.as_secs_f64());
+
+        debug!(
+            "{}: first byte after {:?}, transferred {} bytes in {:?}",
+            self.url,
+            first_byte - self.received,
+            self.bytes,
+            transfer
+        );
+    }
+}
+
+/// Endpoint used by operators to inspect the effective configuration and health of the proxy.
+///
+/// N.B: Secrets (such as storage account keys) must never be included in this response.
+pub async fn status(
+    State(config): State<AppConfig>,
+    State(stats): State<Arc<Stats>>,
+    State(cache_writes): State<Arc<AtomicBool>>,
+) -> Json<serde_json::Value> {
+    let servers = config
+        .servers
+        .iter()
+        .zip(&stats.servers)
+        .map(|(server, health)| {
+            json!({
+                "url": server.url.as_str(),
+                "scope": server.auth.as_ref().map(|a| &a.scope),
+                "path_template": server.path_template,
+                "health": &*health.lock().unwrap(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let cache = config.cache.as_ref().map(|cache| match cache {
+        ConfigCache::Azure(cache) => json!({
+            "type": "azure",
+            "storage_account": cache.storage_account,
+            "storage_container": cache.storage_container,
+        }),
+        ConfigCache::Fs(cache) => json!({
+            "type": "fs",
+            "path": cache.path,
+            "compress_cache": cache.compress_cache,
+        }),
+        #[cfg(feature = "memory-cache")]
+        ConfigCache::Memory(cache) => json!({
+            "type": "memory",
+            "entries": cache.entries().lock().unwrap().len(),
+        }),
+    });
+
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    Json(json!({
+        "servers": servers,
+        "cache": cache,
+        "cache_writes": cache_writes.load(Ordering::Relaxed),
+        "stats": {
+            "requests": load(&stats.requests),
+            "cache_hits": load(&stats.cache_hits),
+            "local_hits": load(&stats.local_hits),
+            "upstream_hits": load(&stats.upstream_hits),
+            "misses": load(&stats.misses),
+            "bytes_served": load(&stats.bytes_served),
+            "bytes_cached": load(&stats.bytes_cached),
+            "in_flight_requests": load(&stats.in_flight_requests),
+            "in_flight_downloads": load(&stats.in_flight_downloads),
+            "peak_in_flight_downloads": load(&stats.peak_in_flight_downloads),
+            "coalesced": load(&stats.coalesced),
+            "mirrors_completed": load(&stats.mirrors_completed),
+            "mirrors_aborted": load(&stats.mirrors_aborted),
+        },
+        "prewarm": {
+            "queued": load(&stats.prewarm_queued),
+            "completed": load(&stats.prewarm_completed),
+            "failed": load(&stats.prewarm_failed),
+            "dropped": load(&stats.prewarm_dropped),
+        },
+    }))
+}
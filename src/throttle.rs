@@ -0,0 +1,65 @@
+//! Bandwidth throttling for symbol streams.
+use std::{sync::Arc, sync::Mutex, time::Duration};
+
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::time::Instant;
+
+/// Limits the rate at which bytes may be transferred, shared between any number of streams.
+pub struct RateLimiter {
+    /// The maximum transfer rate, in bytes per second
+    rate: u64,
+    /// The earliest time at which the next transfer may begin
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `n` bytes may be transferred without exceeding the rate limit.
+    pub async fn acquire(&self, n: usize) {
+        // N.B: Each transfer reserves a slot on a virtual timeline, so concurrent callers are
+        // scheduled one after another rather than all waking at once.
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next).max(now);
+
+            *next = start + Duration::from_secs_f64(n as f64 / self.rate as f64);
+            start - now
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Throttle `stream` such that it does not exceed the rate of any of the given limiters.
+pub fn throttle<S, E>(
+    stream: S,
+    limiters: Vec<Arc<RateLimiter>>,
+) -> impl Stream<Item = Result<Bytes, E>> + Send
+where
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Send,
+{
+    stream.then(move |chunk| {
+        let limiters = limiters.clone();
+
+        async move {
+            if let Ok(chunk) = &chunk {
+                for limiter in &limiters {
+                    limiter.acquire(chunk.len()).await;
+                }
+            }
+
+            chunk
+        }
+    })
+}
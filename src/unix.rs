@@ -0,0 +1,179 @@
+//! Requests to upstream servers listening on Unix domain sockets.
+use std::{future::Future, path::Path, time::Duration};
+
+use anyhow::Context;
+use axum::body::Bytes;
+use futures::StreamExt;
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use tokio::{net::UnixStream, time::Instant};
+use tracing::error;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The timeouts applied to requests sent over a Unix domain socket, which bypass the `reqwest`
+/// client that would otherwise enforce them.
+pub struct Timeouts {
+    /// The time allowed to connect to the socket
+    pub connect: Option<Duration>,
+    /// The time allowed between reads of the response
+    pub read: Option<Duration>,
+}
+
+/// Await `fut`, failing once `limit` has elapsed or `deadline` has passed, whichever is sooner.
+async fn within<F: Future>(
+    deadline: Option<Instant>,
+    limit: Option<Duration>,
+    fut: F,
+) -> std::io::Result<F::Output> {
+    let limit = limit.map(|l| Instant::now() + l);
+    let deadline = match (deadline, limit) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")),
+        None => Ok(fut.await),
+    }
+}
+
+/// Send `request` over the Unix domain socket at `socket`.
+///
+/// The request's URL is only used for its path and `Host` header. Its timeout (if any) bounds the
+/// entire request, including reading the response body.
+pub async fn send(
+    socket: &Path,
+    request: reqwest::RequestBuilder,
+    timeouts: Timeouts,
+) -> anyhow::Result<reqwest::Response> {
+    let request = request.build().context("failed to build request")?;
+    let deadline = request.timeout().map(|t| Instant::now() + *t);
+
+    let (mut sender, conn) = within(deadline, timeouts.connect, async {
+        let stream = UnixStream::connect(socket)
+            .await
+            .with_context(|| format!("failed to connect to {}", socket.display()))?;
+
+        hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .context("failed to establish connection")
+    })
+    .await
+    .with_context(|| format!("timed out connecting to {}", socket.display()))??;
+
+    // The connection must be driven in the background for the request to make progress.
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            error!(
+                "{:?}",
+                anyhow::Error::new(e).context("unix socket connection failed")
+            );
+        }
+    });
+
+    let url = request.url();
+    let mut builder = hyper::Request::builder()
+        .method(request.method().clone())
+        .uri(&url[url::Position::BeforePath..]);
+
+    if let Some(headers) = builder.headers_mut() {
+        *headers = request.headers().clone();
+
+        // N.B: Respect an explicitly provided `Host` header (e.g. from `host_header`).
+        if !headers.contains_key(reqwest::header::HOST) {
+            if let Some(host) = url.host_str() {
+                headers.insert(reqwest::header::HOST, host.parse().context("invalid host")?);
+            }
+        }
+    }
+
+    let body = request
+        .body()
+        .and_then(|b| b.as_bytes())
+        .map(Bytes::copy_from_slice)
+        .unwrap_or_default();
+    let request = builder
+        .body(Full::new(body))
+        .context("failed to build request")?;
+
+    let response = within(deadline, timeouts.read, sender.send_request(request))
+        .await
+        .context("timed out waiting for a response")?
+        .context("failed to send request")?;
+
+    // N.B: Each chunk of the body must arrive in time, too. The stream ends after a timeout.
+    let read = timeouts.read;
+    let response = response.map(|body| {
+        let body = futures::stream::unfold(Some(body.into_data_stream()), move |body| async move {
+            let mut body = body?;
+            match within(deadline, read, body.next()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(body))),
+                Ok(Some(Err(e))) => Some((Err(Box::new(e) as BoxError), Some(body))),
+                Ok(None) => None,
+                Err(e) => Some((Err(Box::new(e) as BoxError), None)),
+            }
+        });
+
+        reqwest::Body::wrap_stream(body)
+    });
+
+    Ok(reqwest::Response::from(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    /// Listen on a socket in `dir` that accepts connections, but never responds to them.
+    fn stalled_socket(dir: &Path) -> std::path::PathBuf {
+        let socket = dir.join("stalled.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        socket
+    }
+
+    async fn send_stalled(request: reqwest::RequestBuilder, timeouts: Timeouts) {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = stalled_socket(dir.path());
+
+        let result =
+            tokio::time::timeout(Duration::from_secs(5), send(&socket, request, timeouts)).await;
+        assert!(result.expect("request was not timed out").is_err());
+    }
+
+    #[tokio::test]
+    async fn read_timeout_applies() {
+        let request = reqwest::Client::new().get("http://localhost/foo.pdb");
+        let timeouts = Timeouts {
+            connect: None,
+            read: Some(Duration::from_millis(100)),
+        };
+
+        send_stalled(request, timeouts).await;
+    }
+
+    #[tokio::test]
+    async fn request_timeout_applies() {
+        let request = reqwest::Client::new()
+            .get("http://localhost/foo.pdb")
+            .timeout(Duration::from_millis(100));
+        let timeouts = Timeouts {
+            connect: None,
+            read: None,
+        };
+
+        send_stalled(request, timeouts).await;
+    }
+}